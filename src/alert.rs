@@ -0,0 +1,146 @@
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{format_eta, format_hash_rate, notify::BlockNotifier};
+
+/// Raised once the EMA hash rate stays under `min_hashrate` for more than `intervals` stats intervals in a row, cleared once it is back
+pub struct HashRateAlert {
+    min_hashrate: f64,
+    intervals: u32,
+    /// Consecutive intervals spent below `min_hashrate`
+    below: u32,
+    raised: bool,
+    /// Also POST raising and clearing to the notify webhook
+    webhook: bool,
+}
+
+impl HashRateAlert {
+    pub fn new(min_hashrate: f64, intervals: u32, webhook: bool) -> Self {
+        HashRateAlert {
+            min_hashrate,
+            intervals,
+            below: 0,
+            raised: false,
+            webhook,
+        }
+    }
+
+    /// Feed one interval's EMA hash rate
+    pub fn update(&mut self, ema: f64, notifier: &BlockNotifier) {
+        let (display_min, min_units) = format_hash_rate(self.min_hashrate);
+        let (display_ema, ema_units) = format_hash_rate(ema);
+        if ema < self.min_hashrate {
+            self.below += 1;
+            if self.below > self.intervals && !self.raised {
+                self.raised = true;
+                let message = format!(
+                    "Hash rate {display_ema:.2} {ema_units} has been below alert.min_hashrate ({display_min:.2} {min_units}) for {} intervals",
+                    self.below
+                );
+                error!("{message}");
+                self.notify(notifier, false, message, ema);
+            }
+            return;
+        }
+        self.below = 0;
+        if self.raised {
+            self.raised = false;
+            let message = format!(
+                "Hash rate recovered to {display_ema:.2} {ema_units}, above alert.min_hashrate ({display_min:.2} {min_units})"
+            );
+            info!("{message}");
+            self.notify(notifier, true, message, ema);
+        }
+    }
+
+    fn notify(&self, notifier: &BlockNotifier, resolved: bool, message: String, ema: f64) {
+        if self.webhook {
+            notifier.alert(json!({
+                "alert": "low_hashrate",
+                "resolved": resolved,
+                "message": message,
+                "ema_hashes_per_second": ema,
+            }));
+        }
+    }
+}
+
+/// Raised once no block has been accepted for `no_block_secs`, counting from startup until the first one, cleared by the next block
+pub struct NoBlockAlert {
+    no_block_secs: u64,
+    /// Unix time the miner started at
+    started: u64,
+    raised: bool,
+    /// Also POST raising and clearing to the notify webhook
+    webhook: bool,
+}
+
+impl NoBlockAlert {
+    pub fn new(no_block_secs: u64, started: u64, webhook: bool) -> Self {
+        NoBlockAlert {
+            no_block_secs,
+            started,
+            raised: false,
+            webhook,
+        }
+    }
+
+    /// Check once per stats interval, `last_block` is the unix time of the last accepted block if there was one
+    pub fn update(
+        &mut self,
+        now: u64,
+        last_block: Option<u64>,
+        eta_secs: Option<f64>,
+        notifier: &BlockNotifier,
+    ) {
+        let since = now.saturating_sub(last_block.unwrap_or(self.started));
+        if since < self.no_block_secs {
+            if self.raised {
+                self.raised = false;
+                info!("Block accepted, clearing the no block alert");
+                self.notify(
+                    notifier,
+                    true,
+                    "Block accepted".to_string(),
+                    since,
+                    eta_secs,
+                );
+            }
+            return;
+        }
+        if self.raised {
+            return;
+        }
+        self.raised = true;
+        let message = format!(
+            "No block accepted for {} (alert.no_block_secs is {}s), expected one every {} at the current hash rate, the node may have stopped accepting blocks or the chain forked",
+            format_eta(since as f64),
+            self.no_block_secs,
+            match eta_secs {
+                Some(eta_secs) => format!("~{}", format_eta(eta_secs)),
+                None => "unknown".to_string(),
+            }
+        );
+        warn!("{message}");
+        self.notify(notifier, false, message, since, eta_secs);
+    }
+
+    fn notify(
+        &self,
+        notifier: &BlockNotifier,
+        resolved: bool,
+        message: String,
+        since: u64,
+        eta_secs: Option<f64>,
+    ) {
+        if self.webhook {
+            notifier.alert(json!({
+                "alert": "no_block",
+                "resolved": resolved,
+                "message": message,
+                "secs_since_block": since,
+                "eta_secs": eta_secs,
+            }));
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use num_bigint::BigUint;
+use snap_coin::{
+    core::block::Block, crypto::address_inclusion_filter::AddressInclusionFilter,
+    economics::GENESIS_PREVIOUS_BLOCK_HASH,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::sleep,
+};
+use tracing::info;
+
+use crate::{
+    format_hash_rate,
+    hashing::{Argon2Params, ProofOfWork},
+    metrics::Metrics,
+    miner::{self, FoundBlock, MinerSettings, MinerShared},
+};
+
+/// Hash a synthetic block on `thread_count` threads for `duration` without a node, then report the hash rate
+/// The block's pow target is all zeros, so no hash ever wins and nothing is submitted
+pub async fn run(
+    thread_count: i32,
+    settings: MinerSettings,
+    argon2: Argon2Params,
+    pow: Arc<dyn ProofOfWork>,
+    duration: Duration,
+) -> Result<(), anyhow::Error> {
+    // memory_cost is in KiB, every thread holds one Argon2 instance at a time
+    let memory_mib = argon2.memory_cost as u64 * thread_count as u64 / 1024;
+    info!(
+        "Benchmarking {thread_count} threads for {}s (about {memory_mib} MiB of Argon2 memory)",
+        duration.as_secs()
+    );
+
+    let block = Block::new_block_now(
+        vec![],
+        &[0u8; 32],
+        &[0u8; 32],
+        GENESIS_PREVIOUS_BLOCK_HASH,
+        &[0u8; 32],
+        AddressInclusionFilter::create_filter(&[])?,
+    );
+    let (job_tx, _) = broadcast::channel::<Block>(1);
+    let (submission_tx, _submission_rx) = mpsc::channel::<FoundBlock>(1);
+    let hash_counters: Vec<Arc<AtomicU64>> = (0..thread_count)
+        .map(|_| Arc::new(AtomicU64::new(0)))
+        .collect();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shared = MinerShared {
+        submission_tx,
+        shutdown: shutdown.clone(),
+        epoch: Arc::new(AtomicU64::new(0)),
+        share_target: Arc::new(ArcSwap::from_pointee(BigUint::ZERO)),
+        settings,
+        pow,
+        metrics: Arc::new(Metrics::default()),
+        paused: Arc::new(AtomicBool::new(false)),
+    };
+
+    let mining_threads =
+        miner::spawn_mining_threads(thread_count, &job_tx, &hash_counters, &shared);
+    job_tx.send(block)?;
+
+    let start = Instant::now();
+    sleep(duration).await;
+    let thread_hashes: Vec<u64> = hash_counters
+        .iter()
+        .map(|counter| counter.load(Ordering::Relaxed))
+        .collect();
+    let elapsed = start.elapsed().as_secs_f64();
+    shutdown.store(true, Ordering::Relaxed);
+    tokio::task::spawn_blocking(move || {
+        for mining_thread in mining_threads {
+            let _ = mining_thread.join();
+        }
+    })
+    .await?;
+
+    let hashes: u64 = thread_hashes.iter().sum();
+    let (display_rate, units) = format_hash_rate(hashes as f64 / elapsed);
+    info!("Benchmark: {hashes} hashes, {display_rate:.2} {units}");
+    for (i, thread_hashes) in thread_hashes.iter().enumerate() {
+        let (display_rate, units) = format_hash_rate(*thread_hashes as f64 / elapsed);
+        info!(thread = i, "{:.2} {}", display_rate, units);
+    }
+
+    Ok(())
+}
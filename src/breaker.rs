@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use tracing::error;
+
+/// Trips after `[node] breaker_failures` node refreshes and submissions in a row fail, pausing mining until a probe gets an answer again
+/// Rejected blocks don't count, the node answered those
+#[derive(Default)]
+pub struct CircuitBreaker {
+    /// Zero, the default until the node source sets it, never trips
+    threshold: AtomicU32,
+    failures: AtomicU32,
+    open: AtomicBool,
+    pub trips: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn set_threshold(&self, threshold: u32) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    pub fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Count a failure, tripping bumps `epoch` so the threads wait for the template that comes once it closes
+    pub fn failed(&self, epoch: &AtomicU64) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = self.threshold.load(Ordering::Relaxed);
+        if threshold > 0 && failures >= threshold && !self.open.swap(true, Ordering::Relaxed) {
+            self.trips.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "The node failed {failures} requests in a row, pausing mining until it answers again (node.breaker_failures)"
+            );
+            epoch.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub fn close(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.open.store(false, Ordering::Relaxed);
+    }
+}
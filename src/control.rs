@@ -0,0 +1,118 @@
+use std::{
+    io::{self, BufRead, IsTerminal},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use anyhow::anyhow;
+use tokio::sync::Notify;
+use tracing::{error, info, info_span, warn};
+
+use crate::{
+    footprint, format_hash_rate, lock::lock, metrics::Metrics, miner::MiningThreads,
+    resolve_thread_count,
+};
+
+/// Everything the commands typed on stdin act on
+pub struct Control {
+    pub metrics: Arc<Metrics>,
+    pub mining: Arc<Mutex<MiningThreads>>,
+    /// Argon2 memory cost of the running threads in KiB, and --force, for the same checks as at startup
+    pub memory_cost: u32,
+    pub force: bool,
+    pub refresh_request: Arc<Notify>,
+    /// Read by every mining thread between batches
+    pub paused: Arc<AtomicBool>,
+    /// Asks main to shut down as on Ctrl-C
+    pub quit: Arc<Notify>,
+}
+
+impl Control {
+    /// Read commands from stdin on a thread of its own, only when stdin is a terminal so piped or detached runs are left alone
+    pub fn spawn(self) {
+        if !io::stdin().is_terminal() {
+            return;
+        }
+        info!("Type stats, threads <count>, pause, resume or quit to control the miner");
+        thread::spawn(move || {
+            let _span = info_span!("control").entered();
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => self.run(line.trim()),
+                    Err(e) => {
+                        warn!("Stopped reading commands from stdin: {e}");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    fn run(&self, command: &str) {
+        match command.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => {}
+            ["stats"] => {
+                let (hash_rate, units) = format_hash_rate(self.metrics.hash_rate());
+                info!(
+                    "Hash rate: {:.2} {} over the last interval, {} threads{}, height {}, diff: {:.3e}, blocks: {} accepted, {} rejected, {} errors",
+                    hash_rate,
+                    units,
+                    lock(&self.mining).thread_count(),
+                    if self.paused.load(Ordering::Relaxed) {
+                        " (paused)"
+                    } else {
+                        ""
+                    },
+                    self.metrics
+                        .height()
+                        .map_or("unknown".to_string(), |height| height.to_string()),
+                    self.metrics.difficulty(),
+                    self.metrics.blocks_found.load(Ordering::Relaxed),
+                    self.metrics.blocks_rejected.load(Ordering::Relaxed),
+                    self.metrics.submit_errors.load(Ordering::Relaxed),
+                );
+            }
+            ["threads", count] => {
+                if let Err(e) = self.resize(count) {
+                    error!("{e}");
+                }
+            }
+            ["pause"] => {
+                if !self.paused.swap(true, Ordering::Relaxed) {
+                    info!("Paused, the threads stop after their current batch");
+                }
+            }
+            ["resume"] => {
+                if self.paused.swap(false, Ordering::Relaxed) {
+                    info!("Resumed");
+                }
+            }
+            ["quit"] => self.quit.notify_one(),
+            _ => warn!(
+                "Unknown command \"{command}\", expected stats, threads <count>, pause, resume or quit"
+            ),
+        }
+    }
+
+    fn resize(&self, count: &str) -> Result<(), anyhow::Error> {
+        let thread_count = count
+            .parse::<i32>()
+            .map_err(|e| anyhow!("Invalid thread count \"{count}\": {e}"))?;
+        let thread_count = resolve_thread_count(thread_count)?;
+        footprint::check(thread_count, self.memory_cost, self.force)?;
+        let mut mining = lock(&self.mining);
+        if thread_count != mining.thread_count() {
+            info!(
+                "Mining on {thread_count} threads, was {}",
+                mining.thread_count()
+            );
+            mining.resize(thread_count);
+            // The new threads are waiting for a template
+            self.refresh_request.notify_one();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+
+use anyhow::anyhow;
+use tracing::{info, warn};
+
+/// Physical and currently available memory in KiB, as reported by `/proc/meminfo`
+struct SystemMemory {
+    total_kib: u64,
+    available_kib: u64,
+}
+
+/// `None` where `/proc/meminfo` is missing (anything but Linux) or unreadable
+fn system_memory() -> Option<SystemMemory> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+    };
+    Some(SystemMemory {
+        total_kib: field("MemTotal")?,
+        available_kib: field("MemAvailable")?,
+    })
+}
+
+/// Log the Argon2 memory `thread_count` threads use and warn when they oversubscribe the cores or the free memory
+/// Every thread holds `memory_cost` KiB while hashing
+/// Needing more than the physical memory is an error unless `force` is set, the miner would only get OOM killed
+pub fn check(thread_count: i32, memory_cost: u32, force: bool) -> Result<(), anyhow::Error> {
+    let thread_kib = memory_cost as u64;
+    let total_kib = thread_kib * thread_count.max(0) as u64;
+    info!(
+        "Argon2 memory: {} MiB per thread, {} MiB for {thread_count} threads",
+        thread_kib / 1024,
+        total_kib / 1024
+    );
+
+    let cores = num_cpus::get();
+    if thread_count as usize > cores {
+        warn!(
+            "Mining on {thread_count} threads with only {cores} cores available, the threads will compete for them and lower the hash rate"
+        );
+    }
+
+    let Some(memory) = system_memory() else {
+        return Ok(());
+    };
+    if total_kib > memory.total_kib {
+        let message = format!(
+            "{thread_count} threads need about {} MiB of memory, more than the {} MiB installed",
+            total_kib / 1024,
+            memory.total_kib / 1024
+        );
+        if !force {
+            return Err(anyhow!(
+                "{message}, lower threads.count or pass --force to start anyway"
+            ));
+        }
+        warn!("{message}, starting anyway (--force)");
+    } else if total_kib > memory.available_kib {
+        warn!(
+            "{thread_count} threads need about {} MiB of memory, more than the {} MiB available",
+            total_kib / 1024,
+            memory.available_kib / 1024
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::anyhow;
+use snap_coin::crypto::Hash;
+
+/// Set once at startup from `[miner] hash_format`
+static HEX: AtomicBool = AtomicBool::new(false);
+
+/// How block hashes are printed, in logs, block log file names and webhooks alike
+#[derive(Clone, Copy, PartialEq)]
+pub enum HashFormat {
+    /// What snap-coin itself prints
+    Base36,
+    /// Lowercase, 64 digits, as most explorers show hashes
+    Hex,
+}
+
+impl HashFormat {
+    pub fn parse(format: &str) -> Result<Self, anyhow::Error> {
+        match format {
+            "base36" => Ok(HashFormat::Base36),
+            "hex" => Ok(HashFormat::Hex),
+            _ => Err(anyhow!(
+                "Invalid miner.hash_format \"{}\", expected \"base36\" or \"hex\"",
+                format
+            )),
+        }
+    }
+}
+
+pub fn init(format: HashFormat) {
+    HEX.store(format == HashFormat::Hex, Ordering::Relaxed);
+}
+
+/// `hash` in the configured format
+pub fn show(hash: &Hash) -> String {
+    if HEX.load(Ordering::Relaxed) {
+        hash.dump_buf()
+            .iter()
+            .fold(String::with_capacity(64), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            })
+    } else {
+        hash.dump_base36()
+    }
+}
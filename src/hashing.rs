@@ -0,0 +1,332 @@
+use std::{cell::RefCell, ops::Range};
+
+use anyhow::anyhow;
+use argon2::{Argon2, Block as MemoryBlock, Params};
+use bincode::{config, error::EncodeError};
+use snap_coin::{
+    core::{block::Block, transaction::Transaction},
+    crypto::{ARGON2_CONFIG, Hash},
+};
+
+/// Argon2 cost parameters blocks are hashed with, the network's `ARGON2_CONFIG` unless overridden in `[argon2]`
+/// The algorithm, version, salt and 32 byte output stay fixed
+#[derive(Clone, Copy, PartialEq)]
+pub struct Argon2Params {
+    /// In KiB, also what every mining thread holds while hashing
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    pub const NETWORK: Argon2Params = Argon2Params {
+        memory_cost: ARGON2_CONFIG.memory_cost,
+        time_cost: ARGON2_CONFIG.time_cost,
+        parallelism: ARGON2_CONFIG.parallelism,
+    };
+
+    /// Fails on parameters Argon2 itself rejects, eg. less than 8 KiB of memory per lane
+    pub fn pow(&self) -> Result<Argon2Pow, anyhow::Error> {
+        let params = Params::new(
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            Some(HASH_LEN),
+        )
+        .map_err(|e| anyhow!("Invalid Argon2 parameters: {e}"))?;
+        Ok(Argon2Pow {
+            argon2: Argon2::new(ARGON2_CONFIG.algorithm, ARGON2_CONFIG.version, params),
+            memory: Memory::PerHash,
+        })
+    }
+}
+
+/// Length of a block hash, `Hash` wraps a fixed size buffer
+pub const HASH_LEN: usize = 32;
+
+/// The proof-of-work function a block's hashing buffer is hashed with, the hash has to beat the block's target
+pub trait ProofOfWork: Send + Sync {
+    fn hash(&self, buf: &[u8]) -> Result<Hash, anyhow::Error>;
+}
+
+/// Argon2 with fixed parameters, salted with the network's magic bytes like `Hash::new`
+/// With `Argon2Params::NETWORK` its hashes are the ones the node verifies
+/// One instance is shared by every mining thread, `Argon2` only holds the parameters and each hash gets its own memory and output buffer
+pub struct Argon2Pow {
+    argon2: Argon2<'static>,
+    memory: Memory,
+}
+
+/// Where a hash's Argon2 memory comes from
+#[derive(Clone, Copy)]
+enum Memory {
+    /// Allocated and freed by every hash, what `hash_password_into` does
+    PerHash,
+    /// Allocated on a thread's first hash and reused by all of its later ones, `[argon2] preallocate`
+    PerThread { huge_pages: bool },
+}
+
+thread_local! {
+    /// A thread's Argon2 memory with `Memory::PerThread`, freed when the thread exits
+    static THREAD_MEMORY: RefCell<Vec<MemoryBlock>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Argon2Pow {
+    /// Keep each thread's Argon2 memory between hashes instead of allocating it for every one
+    /// Every thread that hashes holds on to `memory_cost` until it exits, so this is for the mining threads' hasher only
+    pub fn preallocate(mut self, huge_pages: bool) -> Self {
+        self.memory = Memory::PerThread { huge_pages };
+        self
+    }
+}
+
+impl ProofOfWork for Argon2Pow {
+    fn hash(&self, buf: &[u8]) -> Result<Hash, anyhow::Error> {
+        let mut hash = [0u8; HASH_LEN];
+        match self.memory {
+            Memory::PerHash => {
+                self.argon2
+                    .hash_password_into(buf, &ARGON2_CONFIG.magic_bytes, &mut hash)
+            }
+            Memory::PerThread { huge_pages } => THREAD_MEMORY.with_borrow_mut(|memory| {
+                let block_count = self.argon2.params().block_count();
+                if memory.len() != block_count {
+                    *memory = allocate(block_count, huge_pages);
+                }
+                // Argon2 overwrites every block before reading it, what the last hash left behind doesn't matter
+                self.argon2.hash_password_into_with_memory(
+                    buf,
+                    &ARGON2_CONFIG.magic_bytes,
+                    &mut hash,
+                    &mut memory[..],
+                )
+            }),
+        }
+        .map_err(|e| anyhow!("Argon2 hashing failed: {e}"))?;
+        Ok(Hash::new_from_buf(hash))
+    }
+}
+
+/// `block_count` Argon2 blocks, backed by transparent huge pages if asked for and the kernel allows it
+fn allocate(block_count: usize, huge_pages: bool) -> Vec<MemoryBlock> {
+    let mut memory = Vec::with_capacity(block_count);
+    if huge_pages {
+        advise_huge_pages(&mut memory);
+    }
+    // The first touch is what faults the pages in, after the advice so they come in huge
+    memory.resize(block_count, MemoryBlock::default());
+    memory
+}
+
+/// Ask for huge pages on the untouched capacity of `memory`, only the whole pages inside it can be advised
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(memory: &mut Vec<MemoryBlock>) {
+    use std::sync::Once;
+
+    static FAILED: Once = Once::new();
+    const PAGE_SIZE: usize = 4096;
+    let start = memory.as_mut_ptr() as usize;
+    let end = start + memory.capacity() * MemoryBlock::SIZE;
+    let start = start.next_multiple_of(PAGE_SIZE);
+    if end <= start {
+        return;
+    }
+    // Safety: the range lies within `memory`'s allocation, and madvise only changes how it is backed
+    let result = unsafe {
+        libc::madvise(
+            start as *mut libc::c_void,
+            (end - start) / PAGE_SIZE * PAGE_SIZE,
+            libc::MADV_HUGEPAGE,
+        )
+    };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        FAILED.call_once(|| {
+            tracing::warn!(
+                "Could not get huge pages for the Argon2 memory, using regular ones: {e}"
+            )
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_memory: &mut Vec<MemoryBlock>) {}
+
+/// A block's hashing buffer, serialized once per template, with the byte ranges of the fields that change between nonce trials
+/// The buffer is the bincode encoding of the block with its hash and transaction IO stripped (see `Block::get_hashing_buf`),
+/// which is just its fields in order: transactions, timestamp, nonce, meta
+pub struct HashingTemplate {
+    buf: Vec<u8>,
+    timestamp: Range<usize>,
+    nonce: Range<usize>,
+}
+
+impl HashingTemplate {
+    pub fn new(block: &Block) -> Result<Self, EncodeError> {
+        let transactions: Vec<Transaction> = block
+            .transactions
+            .iter()
+            .map(|tx| Transaction {
+                inputs: vec![],
+                outputs: vec![],
+                transaction_id: tx.transaction_id,
+                nonce: tx.nonce,
+                timestamp: tx.timestamp,
+            })
+            .collect();
+        let mut meta = block.meta.clone();
+        meta.hash = None;
+
+        let mut buf = bincode::encode_to_vec(&transactions, config::standard())?;
+        let fields_start = buf.len();
+        buf.extend_from_slice(&bincode::encode_to_vec(&meta, config::standard())?);
+
+        // Start with empty field ranges, the first patch splices the real values in
+        let mut template = HashingTemplate {
+            buf,
+            timestamp: fields_start..fields_start,
+            nonce: fields_start..fields_start,
+        };
+        template.patch(block.timestamp, block.nonce)?;
+        Ok(template)
+    }
+
+    /// Hashing buffer at a timestamp and nonce, byte for byte what `Block::get_hashing_buf` returns for them
+    pub fn hashing_buf(&mut self, timestamp: u64, nonce: u64) -> Result<&[u8], EncodeError> {
+        self.patch(timestamp, nonce)?;
+        Ok(&self.buf)
+    }
+
+    fn patch(&mut self, timestamp: u64, nonce: u64) -> Result<(), EncodeError> {
+        // A varint encoded u64 takes at most 9 bytes
+        let mut timestamp_bytes = [0u8; 9];
+        let timestamp_len =
+            bincode::encode_into_slice(timestamp, &mut timestamp_bytes, config::standard())?;
+        let mut nonce_bytes = [0u8; 9];
+        let nonce_len = bincode::encode_into_slice(nonce, &mut nonce_bytes, config::standard())?;
+
+        if timestamp_len == self.timestamp.len() && nonce_len == self.nonce.len() {
+            self.buf[self.timestamp.clone()].copy_from_slice(&timestamp_bytes[..timestamp_len]);
+            self.buf[self.nonce.clone()].copy_from_slice(&nonce_bytes[..nonce_len]);
+        } else {
+            // Rare, a value crossed a varint size boundary so the meta bytes after it have to shift
+            let start = self.timestamp.start;
+            self.buf.splice(
+                start..self.nonce.end,
+                timestamp_bytes[..timestamp_len]
+                    .iter()
+                    .chain(&nonce_bytes[..nonce_len])
+                    .copied(),
+            );
+            self.timestamp = start..start + timestamp_len;
+            self.nonce = self.timestamp.end..self.timestamp.end + nonce_len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use snap_coin::{
+        core::transaction::{TransactionInput, TransactionOutput},
+        crypto::{address_inclusion_filter::AddressInclusionFilter, keys::Public},
+    };
+
+    use super::*;
+
+    /// A fixed block with one transaction, so the stripped transaction IO is covered too
+    fn known_answer_block() -> Block {
+        let owner = Public::new_from_buf(&[7u8; 32]);
+        let transactions = vec![Transaction {
+            inputs: vec![TransactionInput {
+                transaction_id: Hash::new_from_buf([1u8; 32]),
+                output_index: 0,
+                signature: None,
+                output_owner: owner,
+            }],
+            outputs: vec![TransactionOutput {
+                amount: 100,
+                receiver: owner,
+            }],
+            transaction_id: Some(Hash::new_from_buf([2u8; 32])),
+            nonce: 42,
+            timestamp: 1_700_000_000,
+        }];
+        let mut block = Block::new_block_now(
+            vec![],
+            &[0xffu8; 32],
+            &[0xffu8; 32],
+            Hash::new_from_buf([3u8; 32]),
+            &[4u8; 32],
+            AddressInclusionFilter::create_filter(&transactions).unwrap(),
+        );
+        block.transactions = transactions;
+        block.timestamp = 1_700_000_000;
+        block.nonce = 250;
+        block
+    }
+
+    /// The network's proof-of-work as of snap-coin 8.7.0, a change here makes every block hashed the old way invalid
+    #[test]
+    fn network_argon2_parameters_are_pinned() {
+        assert_eq!(ARGON2_CONFIG.memory_cost, 8192);
+        assert_eq!(ARGON2_CONFIG.time_cost, 1);
+        assert_eq!(ARGON2_CONFIG.parallelism, 2);
+        assert_eq!(ARGON2_CONFIG.output_length, Some(HASH_LEN));
+        assert_eq!(
+            ARGON2_CONFIG.magic_bytes,
+            [0xcd, 0xc6, 0x3b, 0xaf, 0x5e, 0x52, 0xe0, 0x09, 0x72, 0xad]
+        );
+    }
+
+    #[test]
+    fn known_answer_block_hashes_to_the_pinned_hash() {
+        let block = known_answer_block();
+        let mut template = HashingTemplate::new(&block).unwrap();
+        let hash = Argon2Params::NETWORK
+            .pow()
+            .unwrap()
+            .hash(template.hashing_buf(block.timestamp, block.nonce).unwrap())
+            .unwrap();
+        assert_eq!(
+            hash,
+            Hash::new_from_buf([
+                97, 63, 53, 78, 223, 166, 240, 9, 90, 252, 244, 178, 60, 96, 3, 135, 44, 219, 169,
+                95, 186, 54, 1, 163, 14, 114, 52, 129, 77, 205, 213, 98,
+            ])
+        );
+        // And the node's own path agrees, so a mismatch above is snap-coin changing rather than the template drifting
+        assert_eq!(hash, Hash::new(&block.get_hashing_buf().unwrap()));
+    }
+
+    #[test]
+    fn patched_buffer_matches_the_nodes_across_varint_sizes() {
+        let block = known_answer_block();
+        let mut template = HashingTemplate::new(&block).unwrap();
+        // Both ends of the 1, 3, 5 and 9 byte varints, walked up and back down so both fields grow and shrink
+        let sizes = [
+            0,
+            250,
+            251,
+            u16::MAX as u64,
+            u16::MAX as u64 + 1,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+            u64::MAX,
+        ];
+        let walk = sizes.iter().chain(sizes.iter().rev());
+        for &timestamp in walk.clone() {
+            // The nonce runs the other way, so one field shrinks while the other grows
+            for &nonce in walk.clone().rev() {
+                let mut expected = block.clone();
+                expected.timestamp = timestamp;
+                expected.nonce = nonce;
+                assert_eq!(
+                    template.hashing_buf(timestamp, nonce).unwrap(),
+                    expected.get_hashing_buf().unwrap().as_slice(),
+                    "timestamp {timestamp}, nonce {nonce}"
+                );
+            }
+        }
+    }
+}
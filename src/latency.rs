@@ -0,0 +1,81 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use crate::lock::lock;
+
+/// Upper bounds of the histogram buckets in seconds, the request timeout defaults to 10s
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Latency {
+    /// Requests per bucket, the last one counts those slower than every bound
+    buckets: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+    /// Since the stats task last took the interval's averages
+    interval_count: u64,
+    interval_sum: f64,
+}
+
+/// Wall clock time of the node requests that got an answer, by request name
+#[derive(Default)]
+pub struct NodeLatency {
+    requests: Mutex<BTreeMap<&'static str, Latency>>,
+}
+
+impl NodeLatency {
+    pub fn record(&self, request: &'static str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut requests = lock(&self.requests);
+        let latency = requests.entry(request).or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        latency.buckets[bucket] += 1;
+        latency.count += 1;
+        latency.sum += secs;
+        latency.interval_count += 1;
+        latency.interval_sum += secs;
+    }
+
+    /// Average seconds of each request made since the last call, requests not made since are left out
+    pub fn take_interval(&self) -> Vec<(&'static str, f64)> {
+        lock(&self.requests)
+            .iter_mut()
+            .filter(|(_, latency)| latency.interval_count > 0)
+            .map(|(request, latency)| {
+                let average = latency.interval_sum / latency.interval_count as f64;
+                latency.interval_count = 0;
+                latency.interval_sum = 0.0;
+                (*request, average)
+            })
+            .collect()
+    }
+
+    /// The `snap_miner_node_request_seconds` histogram in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = "# HELP snap_miner_node_request_seconds Wall clock time of node requests that got an answer\n\
+             # TYPE snap_miner_node_request_seconds histogram\n"
+            .to_string();
+        for (request, latency) in lock(&self.requests).iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(latency.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "snap_miner_node_request_seconds_bucket{{request=\"{request}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "snap_miner_node_request_seconds_bucket{{request=\"{request}\",le=\"+Inf\"}} {}\n\
+                 snap_miner_node_request_seconds_sum{{request=\"{request}\"}} {}\n\
+                 snap_miner_node_request_seconds_count{{request=\"{request}\"}} {}",
+                latency.count, latency.sum, latency.count
+            );
+        }
+        out
+    }
+}
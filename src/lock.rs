@@ -0,0 +1,24 @@
+use std::{
+    panic::Location,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use tracing::warn;
+
+/// Lock `mutex` even if a thread panicked holding it, everything shared this way is counters and plain values a half finished update can't break, so one faulty thread doesn't take down every other one that touches it
+#[track_caller]
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            mutex.clear_poison();
+            // Logged with the guard dropped, the log writer of --tui takes a lock of its own
+            drop(poisoned);
+            warn!(
+                "Recovered a lock left poisoned by a panicking thread, at {}",
+                Location::caller()
+            );
+            mutex.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
@@ -1,279 +1,1756 @@
 use anyhow::anyhow;
-use config::Config;
+use arc_swap::ArcSwap;
 use num_bigint::BigUint;
-use rand::{Rng, rng};
 use snap_coin::{
     api::client::Client,
-    blockchain_data_provider::{BlockchainDataProvider, BlockchainDataProviderError},
-    build_block,
-    core::{
-        block::{Block, MAX_TRANSACTIONS_PER_BLOCK},
-        transaction::{Transaction, TransactionId},
-        utils::slice_vec,
-    },
-    crypto::{
-        Hash, address_inclusion_filter::AddressInclusionFilter, keys::Public,
-        merkle_tree::MerkleTree,
-    },
-    economics::{EXPIRATION_TIME, get_block_reward},
-    to_snap,
+    blockchain_data_provider::BlockchainDataProvider,
+    core::block::{Block, MAX_TRANSACTIONS_PER_BLOCK},
+    crypto::keys::Public,
+    economics::to_snap,
 };
 use std::{
     env::args,
-    fs::{self, File},
-    io::Write,
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process::ExitCode,
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{
-    sync::{RwLock, broadcast, mpsc},
-    time::sleep,
+    net::TcpListener,
+    signal,
+    sync::{Notify, RwLock, broadcast, mpsc},
+    time::{sleep, timeout},
 };
+use tracing::{Instrument, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    alert::{HashRateAlert, NoBlockAlert},
+    hash_format::HashFormat,
+    hashing::{Argon2Params, HASH_LEN},
+    history::RateHistory,
+    lock::lock,
+    metrics::Metrics,
+    miner::{FoundBlock, MinerSettings, MinerShared, MiningThreads, panic_reason},
+    node::{Broadcast, Connection, NodeAddress, NodeSource},
+    notify::{BlockNotifier, Webhook},
+    payout::Payouts,
+    pidfile::PidFile,
+    pool::PoolClient,
+    settings::{NodeConfig, PublicField},
+    style::ColorChoice,
+    tls::NodeTls,
+    tui::{Dashboard, TuiLog},
+    tx_selector::{AllTxs, AtMost, NoTxs},
+    work::WorkSource,
+};
+
+mod alert;
+mod bench;
+mod breaker;
+mod control;
+mod footprint;
+mod hash_format;
+mod hashing;
+mod history;
+mod latency;
+mod lock;
+mod metrics;
+mod miner;
+#[cfg(test)]
+mod mock;
+mod node;
+mod nonce;
+mod notify;
+mod payout;
+mod pidfile;
+mod pool;
+#[cfg(unix)]
+mod reload;
+mod settings;
+mod style;
+mod systemd;
+mod tls;
+mod tui;
+mod tx_selector;
+mod work;
 
 fn format_hash_rate(hps: f64) -> (f64, &'static str) {
-    const UNITS: [&str; 5] = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s"];
+    scale_units(hps, &["H/s", "kH/s", "MH/s", "GH/s", "TH/s"])
+}
+
+fn format_hash_count(hashes: u64) -> (f64, &'static str) {
+    scale_units(hashes as f64, &["H", "kH", "MH", "GH", "TH"])
+}
 
-    let mut rate = hps;
+/// Divide by 1000 until the value fits the largest unit it reaches
+fn scale_units(value: f64, units: &[&'static str]) -> (f64, &'static str) {
+    let mut value = value;
     let mut unit = 0;
 
-    while rate >= 1000.0 && unit < UNITS.len() - 1 {
-        rate /= 1000.0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
         unit += 1;
     }
 
-    (rate, UNITS[unit])
+    (value, units[unit])
+}
+
+/// Uptime as hours and minutes, eg. "0h14m"
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    format!("{}h{:02}m", secs / 3600, secs / 60 % 60)
+}
+
+/// Expected seconds until a hash beats `target`, `None` while there is no hash rate or target to go on
+/// Every hash is uniform over the 2^256 hash space, so one in 2^256 / target wins
+fn expected_secs_to_block(hash_rate: f64, target: f64) -> Option<f64> {
+    if hash_rate <= 0.0 || target <= 0.0 {
+        return None;
+    }
+    Some(2f64.powi(256) / target / hash_rate)
+}
+
+/// Fewest, median and most hashes an accepted block took, `None` before the first one
+/// The median of an even count is the mean of the two middle blocks
+fn block_hash_summary(block_hashes: &[u64]) -> Option<(u64, u64, u64)> {
+    let mut sorted = block_hashes.to_vec();
+    sorted.sort_unstable();
+    let middle = sorted.len() / 2;
+    let median = if sorted.len().is_multiple_of(2) {
+        (*sorted.get(middle.checked_sub(1)?)? + sorted[middle]) / 2
+    } else {
+        sorted[middle]
+    };
+    Some((sorted[0], median, *sorted.last()?))
+}
+
+/// Hash rate that finds one block per `target_block_time_secs` on average at `target`, `None` without a target
+fn network_hash_rate(target: f64, target_block_time_secs: u64) -> Option<f64> {
+    if target <= 0.0 {
+        return None;
+    }
+    Some(2f64.powi(256) / target / target_block_time_secs as f64)
+}
+
+/// Like `format_uptime`, but short waits are shown in seconds
+fn format_eta(secs: f64) -> String {
+    if secs < 60.0 {
+        format!("{:.0}s", secs)
+    } else {
+        format_uptime(Duration::from_secs_f64(secs))
+    }
+}
+
+/// Base wait between block submission retries, multiplied by the attempt number
+const SUBMIT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long `--bench` hashes when no duration is given
+const DEFAULT_BENCH_SECS: u64 = 10;
+
+/// Longest wait between two reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How often a node that tripped `[node] breaker_failures` is probed
+const BREAKER_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often `[node] require_synced` and `min_peers` ask the node for its peers
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stats intervals the peak, min and median hash rate are taken over, an hour at the default 3s interval
+const HASH_RATE_SAMPLES: usize = 1200;
+
+/// Connect the request and the event client to the first reachable node, in configured order
+/// A node that doesn't accept both connections within `connect_timeout` counts as unreachable
+async fn connect_first_available(
+    nodes: &[NodeAddress],
+    connect_timeout: Duration,
+    tls: Option<&NodeTls>,
+) -> Option<(NodeAddress, Client, Client)> {
+    for node in nodes {
+        match timeout(connect_timeout, connect_node(node, tls)).await {
+            Ok(Ok((client, event_client))) => return Some((node.clone(), client, event_client)),
+            Ok(Err(e)) => warn!("Failed to connect to node {node}: {e}"),
+            Err(_) => warn!(
+                "Timed out connecting to node {node} after {}s",
+                connect_timeout.as_secs()
+            ),
+        }
+    }
+    None
+}
+
+/// Connect both clients to `node`, each through its own tunnel with TLS
+async fn connect_node(
+    node: &NodeAddress,
+    tls: Option<&NodeTls>,
+) -> Result<(Client, Client), std::io::Error> {
+    Ok((node.connect(tls).await?, node.connect(tls).await?))
 }
 
-const DEFAULT_CONFIG: &str = "[node]
+/// Reconnect to the first reachable node, waiting with exponential backoff (1s, 2s, 4s... capped at 30s) before each pass over the node list
+/// Waiting before the first pass too keeps a node that accepts and immediately drops connections from being hammered
+async fn connect_with_backoff(
+    nodes: &[NodeAddress],
+    connect_timeout: Duration,
+    tls: Option<&NodeTls>,
+) -> (NodeAddress, Client, Client) {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        sleep(delay).await;
+        info!("Connecting to nodes (attempt {attempt})");
+        if let Some(connection) = connect_first_available(nodes, connect_timeout, tls).await {
+            return connection;
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        attempt += 1;
+    }
+}
+
+/// `-1` stands for every available core
+fn resolve_thread_count(thread_count: i32) -> Result<i32, anyhow::Error> {
+    match thread_count {
+        -1 => Ok(thread::available_parallelism()?.get() as i32),
+        1.. => Ok(thread_count),
+        _ => Err(anyhow!(
+            "The thread count must be at least 1, or -1 for every core, got {}",
+            thread_count
+        )),
+    }
+}
+
+/// Nodes in the order they are tried, --node replaces node.address but fallbacks from node.addresses still apply
+fn node_addresses(node_override: Option<&NodeAddress>, node: &NodeConfig) -> Vec<String> {
+    let mut addresses: Vec<String> = match node_override {
+        Some(node) => vec![node.to_string()],
+        None => node.address.iter().cloned().collect(),
+    };
+    addresses.extend(node.addresses.iter().cloned());
+    addresses
+}
+
+fn parse_nodes(addresses: &[String]) -> Result<Vec<NodeAddress>, anyhow::Error> {
+    addresses
+        .iter()
+        .map(|address| NodeAddress::parse(address))
+        .collect()
+}
+
+/// How the periodic stats line is printed
+#[derive(Clone, Copy, PartialEq)]
+enum StatsFormat {
+    Human,
+    Json,
+}
+
+impl StatsFormat {
+    fn parse(format: &str) -> Result<Self, anyhow::Error> {
+        match format {
+            "human" => Ok(StatsFormat::Human),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(anyhow!(
+                "Invalid stats.format \"{}\", expected \"human\" or \"json\"",
+                format
+            )),
+        }
+    }
+}
+
+/// The miner.public value written to a freshly generated config
+const PUBLIC_PLACEHOLDER: &str = "<your public wallet address>";
+
+const USAGE: &str = "Usage: snap-coin-miner [--config <path>] [--threads <count>] [--node <host:port>] [--bench [secs]] [--dry-run] [--force] [--color auto|always|never] [--quiet | --verbose] [--pidfile <path>] [--tui] [--validate] [--init]";
+
+const HELP: &str = "Options:
+  --config <path>     Path to the toml miner config (default ./miner.toml)
+  --threads <count>   Override threads.count, -1 for every available core
+  --node <host:port>  Override node.address
+  --bench [secs]      Hash a synthetic block without a node for secs (default 10) and print the hash rate
+  --dry-run           Mine against the node but only log found blocks instead of submitting them
+  --force             Start even if the threads need more memory than is installed
+  --color <when>      Color log output: auto (on a terminal without NO_COLOR, the default), always or never
+  --quiet             Only log found blocks, warnings and errors, overrides log.level
+  --verbose           Also log every template refresh, difficulty change and batch's best hash, overrides log.level
+  --pidfile <path>    Write the PID to path while running, refusing to start if it names a running process
+  --tui               Show a live dashboard instead of the scrolling log when stdout is a terminal, q quits
+  --validate          Check the config and that the node or pool answers, then exit without mining
+  --init              Write the default config to the --config path and exit, --force overwrites an existing file
+  --version           Print the miner version
+  --help              Print this help";
+
+/// Log filters `--quiet` and `--verbose` stand in for log.level with, RUST_LOG still takes precedence
+const QUIET_LOG_FILTER: &str = "warn,[submissions]=info";
+const VERBOSE_LOG_FILTER: &str = "info,snap_coin_miner=debug";
+
+/// Flags that take a value (the value is skipped when checking for unknown flags)
+/// `--seed` is left out of the help on purpose, it only exists for reproducible test runs
+const VALUE_FLAGS: [&str; 6] = [
+    "--config",
+    "--threads",
+    "--node",
+    "--color",
+    "--pidfile",
+    "--seed",
+];
+
+/// Flags that take no value, or an optional one
+const SWITCH_FLAGS: [&str; 8] = [
+    "--bench",
+    "--dry-run",
+    "--force",
+    "--quiet",
+    "--verbose",
+    "--tui",
+    "--validate",
+    "--init",
+];
+
+const DEFAULT_CONFIG: &str = "# Any key can also be set through the environment as SNAP_MINER_<SECTION>_<KEY>, which takes precedence over this file
+# eg. SNAP_MINER_NODE_ADDRESS=127.0.0.1:3003 or SNAP_MINER_THREADS_COUNT=-1, node.addresses and miner.public take comma separated lists
+# [[miner.payouts]] can only be set here
+
+[node]
+# <ip>:<port> or <hostname>:<port>
 address = \"127.0.0.1:3003\"
+# Fallback nodes, tried in order after address when the active node is unreachable
+# addresses = [\"127.0.0.1:3004\"]
+# Seconds between periodic block template refreshes, on top of chain events (minimum 1)
+refresh_interval_secs = 3
+# How many times a block submission is retried after a network error
+submit_retries = 3
+# Submit found blocks to every configured node at once, accepted as soon as one node accepts, each node gets a single attempt
+broadcast_submit = false
+# Milliseconds a block submission waits after the previous one, to avoid flooding the node when threads solve in a burst (0 for no limit)
+min_submit_interval_ms = 0
+# Pause mining while the node reports no peers, checked every 10s, as blocks on an isolated node's tip get orphaned (turn off for a private test node)
+require_synced = true
+# Pause mining while the node has fewer peers than this, checked every 10s like require_synced (0 to only rely on require_synced)
+min_peers = 0
+# Seconds the network aims to spend per block, used to estimate the network hash rate
+target_block_time_secs = 20
+# Connect to the node over TLS, checking its certificate against the bundled web roots or only the CA certificates in ca_cert (PEM)
+# The TLS session is reached through a local loopback port, on Linux only the miner's own connection to it is taken, elsewhere another local user could connect first
+tls = false
+# ca_cert = \"node-ca.pem\"
+# Seconds before connecting to a node or a request to it is given up on, a timed out request reconnects (minimum 1)
+connect_timeout_secs = 10
+request_timeout_secs = 10
+# Milliseconds after which an answered node request is logged as slow, slow template requests leave the threads on stale work (0 never warns)
+slow_request_ms = 1000
+# Template refreshes and block submissions failing in a row before mining pauses, the node is then probed every 10s until it answers (0 never pauses)
+breaker_failures = 5
 
 [miner]
+# One wallet address, or a list of them (public = [\"...\", \"...\"]) that block templates pay out to in turn
 public = \"<your public wallet address>\"
+# Hashes between checks for a new block template (minimum 1), larger batches spend longer on stale work
+batch_size = 20
+# Share of the time each mining thread spends hashing (1-100), the rest is slept off between batches
+max_cpu_percent = 100
+# Bind every mining thread to its own core (thread i on core i % cores)
+pin_threads = false
+# Stamp every hash with the current time, so a long lived template never goes out with an old timestamp
+refresh_timestamp = true
+# Log the balance of every payout address every balance_interval_secs seconds (minimum 1)
+show_balance = false
+balance_interval_secs = 60
+# Write every accepted block as JSON to <block_log_dir>/<hash>.json, the directory is created if missing
+# block_log_dir = \"blocks\"
+# Mempool transactions a template includes at most (0-499), 0 mines empty blocks
+max_txs_per_block = 499
+# Mine blocks holding only the reward transaction without ever fetching the mempool
+empty_blocks = false
+# How block hashes are printed in logs, block_log_dir file names and webhooks: \"base36\" like snap-coin, or \"hex\"
+hash_format = \"base36\"
+# Instead of public, split templates between wallets at random in proportion to their weight (eg. 70/30)
+# [[miner.payouts]]
+# address = \"<first wallet address>\"
+# weight = 70
+# [[miner.payouts]]
+# address = \"<second wallet address>\"
+# weight = 30
 
 [threads]
-count = 1";
+count = 1
+
+[stats]
+# Seconds between hash rate reports (minimum 1)
+interval_secs = 3
+# \"human\" or \"json\" (one JSON object per line)
+format = \"human\"
+# Smoothing factor of the moving average hash rate, in (0, 1], higher follows the latest interval more closely
+ema_alpha = 0.3
+# Seconds after startup that are left out of the hash rate and totals (0 to report from the start)
+warmup_secs = 5
+# Append unix_ts,hashes_per_second,total_hashes,blocks_found every interval to this CSV file
+# csv_path = \"stats.csv\"
+
+[log]
+# Default log filter (eg. \"info\", \"warn\", \"snap_coin_miner=debug\"), RUST_LOG takes precedence
+level = \"info\"
+
+# [pool]
+# Mine for a Stratum-like pool at <host>:<port> instead of solo against node.address, within node.connect_timeout_secs
+# url = \"stratum+tcp://127.0.0.1:4444\"
+# Defaults to miner.public
+# worker = \"rig1\"
+
+# [metrics]
+# Serve Prometheus metrics on http://<listen>/metrics
+# listen = \"127.0.0.1:9110\"
+
+# [notify]
+# POST a JSON summary of every accepted block (hash, height, thread, time_to_find_secs) to this plain http:// url
+# webhook_url = \"http://127.0.0.1:8080/snap-blocks\"
+
+# [alert]
+# Log an error once the moving average hash rate (in H/s) stays below min_hashrate for more than min_hashrate_intervals stats intervals
+# min_hashrate = 100
+# min_hashrate_intervals = 5
+# Solo mining only: warn once no block has been accepted for this many seconds
+# no_block_secs = 3600
+# Also POST alerts, and their recovery, to notify.webhook_url
+# webhook = false
+
+# [argon2]
+# Allocate each mining thread's Argon2 memory once instead of for every hash
+# preallocate = true
+# With preallocate, back that memory with transparent huge pages (Linux only)
+# huge_pages = false
+# Testnets only: hash with other Argon2 parameters than the network, blocks are then only valid to a node using the same ones
+# memory_cost = 8192
+# time_cost = 1
+# parallelism = 2";
+
+/// Why startup failed, for the exit code, any other error exits with 1
+#[derive(Clone, Copy, Debug)]
+enum ExitReason {
+    /// Missing or invalid config, also the default for anything failing before the config is fully checked
+    Config = 2,
+    NodeUnreachable = 3,
+    InvalidMinerKey = 4,
+    /// Refused to start, the pidfile can't be taken (another miner holds it) or, without --force, the threads' Argon2 memory is more than is installed
+    Refused = 5,
+}
+
+impl ExitReason {
+    /// Tag `error` so main exits with this reason's code
+    fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Fatal {
+            reason: self,
+            error,
+        })
+    }
+}
+
+/// An error that exits with its reason's code, shown exactly like the error it wraps
+struct Fatal {
+    reason: ExitReason,
+    error: anyhow::Error,
+}
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::fmt::Debug for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Fatal {}
 
 #[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
+async fn main() -> ExitCode {
+    // Set once the config is fully checked, errors before that count as config errors
+    let configured = AtomicBool::new(false);
+    match run(&configured).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // The same output returning the error from main would give
+            eprintln!("Error: {e:?}");
+            let reason = match e.downcast_ref::<Fatal>() {
+                Some(fatal) => Some(fatal.reason),
+                None if !configured.load(Ordering::Relaxed) => Some(ExitReason::Config),
+                None => None,
+            };
+            ExitCode::from(reason.map_or(1, |reason| reason as u8))
+        }
+    }
+}
+
+async fn run(configured: &AtomicBool) -> Result<(), anyhow::Error> {
     let mut config_path = "./miner.toml";
+    let mut thread_count_override = None;
+    let mut node_override = None;
+    let mut bench_secs = None;
+    let mut seed = None;
+    let mut color = ColorChoice::Auto;
+    let mut pidfile = None;
+
+    let args: Vec<String> = args().collect();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let force = args.iter().any(|arg| arg == "--force");
+    let quiet = args.iter().any(|arg| arg == "--quiet");
+    let verbose = args.iter().any(|arg| arg == "--verbose");
+    let tui = args.iter().any(|arg| arg == "--tui");
+    let validate = args.iter().any(|arg| arg == "--validate");
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{USAGE}\n\n{HELP}");
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--version") {
+        println!("snap-coin-miner {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
 
-    let args: Vec<String> = args().into_iter().collect();
     for (place, arg) in args.iter().enumerate() {
+        let is_flag_value = place > 0 && VALUE_FLAGS.contains(&args[place - 1].as_str());
+        if arg.starts_with("--")
+            && !is_flag_value
+            && !VALUE_FLAGS.contains(&arg.as_str())
+            && !SWITCH_FLAGS.contains(&arg.as_str())
+        {
+            return Err(anyhow!("Unrecognized argument {}\n{}", arg, USAGE));
+        }
         if arg == "--config" && args.get(place + 1).is_some() {
             config_path = &args[place + 1];
         }
+        if arg == "--threads" && args.get(place + 1).is_some() {
+            thread_count_override = Some(
+                args[place + 1]
+                    .parse::<i32>()
+                    .map_err(|e| anyhow!("Invalid --threads \"{}\": {}", args[place + 1], e))?,
+            );
+        }
+        if arg == "--node" && args.get(place + 1).is_some() {
+            node_override = Some(
+                NodeAddress::parse(&args[place + 1]).map_err(|e| anyhow!("Invalid --node: {e}"))?,
+            );
+        }
+        if arg == "--pidfile" && args.get(place + 1).is_some() {
+            pidfile = Some(args[place + 1].as_str());
+        }
+        if arg == "--color" && args.get(place + 1).is_some() {
+            color = ColorChoice::parse(&args[place + 1])?;
+        }
+        if arg == "--seed" && args.get(place + 1).is_some() {
+            seed = Some(
+                args[place + 1]
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("Invalid --seed \"{}\": {}", args[place + 1], e))?,
+            );
+        }
+        if arg == "--bench" {
+            // The duration is optional, so only a following non-flag argument is taken as one
+            bench_secs = Some(match args.get(place + 1) {
+                Some(secs) if !secs.starts_with("--") => secs
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("Invalid --bench duration \"{}\": {}", secs, e))?,
+                _ => DEFAULT_BENCH_SECS,
+            });
+        }
     }
 
-    if !fs::exists(config_path).is_ok_and(|exists| exists == true) {
-        File::create(config_path)?.write(DEFAULT_CONFIG.as_bytes())?;
+    if args.iter().any(|arg| arg == "--init") {
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(force)
+            .create_new(!force)
+            .open(config_path)
+            .and_then(|mut file| file.write_all(DEFAULT_CONFIG.as_bytes()))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => anyhow!(
+                    "Config file {} already exists, pass --force to overwrite it",
+                    config_path
+                ),
+                _ => anyhow!("Could not write config file {}: {}", config_path, e),
+            })?;
+        println!(
+            "Wrote the default config to {config_path}, replace {PUBLIC_PLACEHOLDER} in it with your real miner address"
+        );
+        return Ok(());
+    }
+
+    if !fs::exists(config_path).is_ok_and(|exists| exists) {
+        if validate {
+            return Err(anyhow!("Config file {} does not exist", config_path));
+        }
+        File::create(config_path)?.write_all(DEFAULT_CONFIG.as_bytes())?;
         return Err(anyhow!(
             "Created new config file: {}. Please replace <your public wallet address> in the config with your real miner address",
             config_path
         ));
     }
 
-    let settings = Config::builder()
-        .add_source(config::File::with_name("miner.toml"))
-        .build()?;
+    let miner_config = settings::load(config_path)?;
+    // What a SIGHUP reload is compared against
+    let loaded_config = miner_config.clone();
 
-    let node_address: String = settings.get("node.address")?;
-    let public_key_base36: String = settings.get("miner.public")?;
-    let thread_count: i32 = settings.get("threads.count")?;
-    let thread_count = if thread_count == -1 {
-        thread::available_parallelism()?.get() as i32
+    if quiet && verbose {
+        return Err(anyhow!(
+            "--quiet and --verbose can't be combined\n{}",
+            USAGE
+        ));
+    }
+    if validate && bench_secs.is_some() {
+        return Err(anyhow!(
+            "--validate and --bench can't be combined\n{}",
+            USAGE
+        ));
+    }
+    // Events of the submissions task stay at info, so accepted blocks still show when quiet
+    let log_level = match (quiet, verbose) {
+        (true, _) => QUIET_LOG_FILTER,
+        (_, true) => VERBOSE_LOG_FILTER,
+        _ => &miner_config.log.level,
+    };
+    let log_filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(log_level)
+            .map_err(|e| anyhow!("Invalid log.level \"{}\": {}", log_level, e))?,
+    };
+    // The dashboard needs the terminal to itself, and has no use for escapes in its log pane
+    let tui = tui && io::stdout().is_terminal();
+    let tui_log = TuiLog::new();
+    // The style helpers emit their own escapes, which the formatter would otherwise print literally
+    let colored = style::init(if tui { ColorChoice::Never } else { color });
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter)
+        .with_ansi(colored)
+        .with_ansi_sanitization(!colored)
+        .with_writer(tui_log.make_writer())
+        .init();
+    if args.iter().any(|arg| arg == "--tui") && !tui {
+        warn!("--tui needs stdout to be a terminal, logging plainly");
+    }
+    // Held until main returns, dropping it removes the file
+    // Validating next to a running miner mustn't trip over its pidfile
+    let _pidfile = pidfile
+        .filter(|_| !validate)
+        .map(|path| PidFile::create(Path::new(path)))
+        .transpose()
+        .map_err(|e| ExitReason::Refused.wrap(e))?;
+
+    // The command line takes precedence over the config file
+    let thread_count: i32 = match thread_count_override {
+        Some(thread_count) => thread_count,
+        None => miner_config.threads.count.ok_or_else(|| {
+            anyhow!(
+                "threads.count is not set in {}, use -1 for every available core",
+                config_path
+            )
+        })?,
+    };
+    let thread_count = resolve_thread_count(thread_count)?;
+
+    let argon2_config = &miner_config.argon2;
+    if argon2_config.output_length != HASH_LEN {
+        return Err(anyhow!(
+            "argon2.output_length must be {} (block hashes are fixed size), got {}",
+            HASH_LEN,
+            argon2_config.output_length
+        ));
+    }
+    let argon2 = Argon2Params {
+        memory_cost: argon2_config.memory_cost,
+        time_cost: argon2_config.time_cost,
+        parallelism: argon2_config.parallelism,
+    };
+    let pow = argon2.pow()?;
+    if argon2 != Argon2Params::NETWORK {
+        warn!(
+            "!!! Argon2 parameters overridden (memory_cost {}, time_cost {}, parallelism {}), only a node hashing with the same parameters accepts these blocks !!!",
+            argon2.memory_cost, argon2.time_cost, argon2.parallelism
+        );
+    }
+    footprint::check(thread_count, argon2.memory_cost, force)
+        .map_err(|e| ExitReason::Refused.wrap(e))?;
+    if argon2_config.huge_pages && !argon2_config.preallocate {
+        warn!("argon2.huge_pages only applies with argon2.preallocate, ignoring it");
+    } else if argon2_config.huge_pages && !cfg!(target_os = "linux") {
+        warn!("argon2.huge_pages is only supported on Linux, ignoring it");
+    }
+    let pow = Arc::new(if argon2_config.preallocate {
+        pow.preallocate(argon2_config.huge_pages)
     } else {
-        thread_count
+        pow
+    });
+
+    let batch_size = miner_config.miner.batch_size;
+    if batch_size < 1 {
+        return Err(anyhow!("miner.batch_size must be at least 1"));
+    }
+    let max_cpu_percent = miner_config.miner.max_cpu_percent;
+    if !(1..=100).contains(&max_cpu_percent) {
+        return Err(anyhow!(
+            "miner.max_cpu_percent must be between 1 and 100, got {}",
+            max_cpu_percent
+        ));
+    }
+    let miner_settings = MinerSettings {
+        batch_size,
+        max_cpu_percent,
+        pin_threads: miner_config.miner.pin_threads,
+        refresh_timestamp: miner_config.miner.refresh_timestamp,
+        seed,
     };
+    if let Some(seed) = seed {
+        warn!("Mining with --seed {seed}, nonces are reproducible, for testing only");
+    }
 
-    let miner_pub = Public::new_from_base36(&public_key_base36).expect("Invalid public key");
-    let client = Arc::new(Client::connect(node_address.parse().unwrap()).await?);
-    let event_client = Client::connect(node_address.parse().unwrap()).await?;
+    if let Some(bench_secs) = bench_secs {
+        configured.store(true, Ordering::Relaxed);
+        return bench::run(
+            thread_count,
+            miner_settings,
+            argon2,
+            pow,
+            Duration::from_secs(bench_secs),
+        )
+        .await;
+    }
 
-    // A task for block submissions, submitted via MPSC and a new block transmitted to all mining threads via a broadcast.
-    let (submission_tx, mut submission_rx) = mpsc::channel::<Block>(1);
+    let pool_address = miner_config
+        .pool
+        .url
+        .as_ref()
+        .map(|url| {
+            NodeAddress::parse(url.strip_prefix("stratum+tcp://").unwrap_or(url))
+                .map_err(|e| anyhow!("Invalid pool.url: {e}"))
+        })
+        .transpose()?;
 
-    let (job_tx, _) = broadcast::channel::<Block>(64);
+    let node_addresses = node_addresses(node_override.as_ref(), &miner_config.node);
+    if node_addresses.is_empty() && pool_address.is_none() {
+        return Err(anyhow!(
+            "No node configured, set node.address, node.addresses or pool.url"
+        ));
+    }
+    let refresh_interval_secs = miner_config.node.refresh_interval_secs;
+    if refresh_interval_secs < 1 {
+        return Err(anyhow!(
+            "node.refresh_interval_secs must be at least 1 second, got {}",
+            refresh_interval_secs
+        ));
+    }
+    let submit_retries = miner_config.node.submit_retries;
+    let target_block_time_secs = miner_config.node.target_block_time_secs;
+    if target_block_time_secs < 1 {
+        return Err(anyhow!(
+            "node.target_block_time_secs must be at least 1 second, got {}",
+            target_block_time_secs
+        ));
+    }
+    let connect_timeout_secs = miner_config.node.connect_timeout_secs;
+    if connect_timeout_secs < 1 {
+        return Err(anyhow!(
+            "node.connect_timeout_secs must be at least 1 second, got {}",
+            connect_timeout_secs
+        ));
+    }
+    let connect_timeout = Duration::from_secs(connect_timeout_secs);
+    let request_timeout_secs = miner_config.node.request_timeout_secs;
+    if request_timeout_secs < 1 {
+        return Err(anyhow!(
+            "node.request_timeout_secs must be at least 1 second, got {}",
+            request_timeout_secs
+        ));
+    }
+    let slow_request = Duration::from_millis(miner_config.node.slow_request_ms);
+    let breaker_failures = miner_config.node.breaker_failures;
+    let broadcast_submit = miner_config.node.broadcast_submit;
+    // require_synced is the same check with a single peer required
+    let required_peers = miner_config
+        .node
+        .min_peers
+        .max(miner_config.node.require_synced as usize);
+    let min_submit_interval = Duration::from_millis(miner_config.node.min_submit_interval_ms);
+    let tls = if miner_config.node.tls {
+        Some(NodeTls::new(miner_config.node.ca_cert.as_deref())?)
+    } else {
+        if miner_config.node.ca_cert.is_some() {
+            warn!("node.ca_cert is set but node.tls is off, connecting in plaintext");
+        }
+        None
+    };
+    let max_txs_per_block = miner_config.miner.max_txs_per_block;
+    if max_txs_per_block > MAX_TRANSACTIONS_PER_BLOCK - 1 {
+        return Err(anyhow!(
+            "miner.max_txs_per_block can be at most {} (the reward transaction takes the last slot), got {}",
+            MAX_TRANSACTIONS_PER_BLOCK - 1,
+            max_txs_per_block
+        ));
+    }
+    let empty_blocks = miner_config.miner.empty_blocks;
+    let show_balance = miner_config.miner.show_balance;
+    let balance_interval_secs = miner_config.miner.balance_interval_secs;
+    if show_balance && balance_interval_secs < 1 {
+        return Err(anyhow!(
+            "miner.balance_interval_secs must be at least 1 second, got {}",
+            balance_interval_secs
+        ));
+    }
+    // [[miner.payouts]] tables with weights take precedence over miner.public
+    let payout_entries = miner_config.miner.payouts;
+    let payout_field = if payout_entries.is_some() {
+        "[[miner.payouts]] address"
+    } else {
+        "[miner] public"
+    };
+    let miner_publics_base36: Vec<String> = match &payout_entries {
+        Some(entries) => entries.iter().map(|entry| entry.address.clone()).collect(),
+        // A single address or an array of them, rotated between templates
+        None => match miner_config.miner.public {
+            Some(PublicField::One(public)) => vec![public],
+            Some(PublicField::Many(publics)) => publics,
+            None => {
+                return Err(anyhow!(
+                    "The [miner] public field is missing from {}, set it to your wallet address",
+                    config_path
+                ));
+            }
+        },
+    };
+    if miner_publics_base36.is_empty() {
+        return Err(anyhow!(
+            "The {} field in {} is empty, add at least one wallet address",
+            payout_field,
+            config_path
+        ));
+    }
+    if !validate {
+        info!("Mining on {thread_count} threads");
+    }
+    if dry_run {
+        warn!("Dry run, found blocks are logged but never submitted");
+    }
+    if empty_blocks && pool_address.is_none() {
+        info!("Mining empty blocks, the mempool is never fetched");
+    }
 
-    let hash_counter = Arc::new(AtomicU64::new(0));
-
-    // Create mining threads
-    for i in 0..thread_count {
-        println!("[THREAD {i}] Starting miner");
-        let mut job_rx = job_tx.subscribe();
-        let submission_tx = submission_tx.clone();
-        let hash_counter = hash_counter.clone();
-        thread::spawn(move || {
-            // At startup wait for block thread to create a block
-            let mut current_block = loop {
-                match job_rx.blocking_recv() {
-                    Ok(v) => break v,
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
-                    Err(broadcast::error::RecvError::Closed) => return,
-                }
-            };
+    let stats_interval_secs = miner_config.stats.interval_secs;
+    if stats_interval_secs == 0 {
+        return Err(anyhow!(
+            "stats.interval_secs must be at least 1 second, a 0 second interval has no hash rate"
+        ));
+    }
 
-            let mut rng = rng();
-            loop {
-                if let Err(e) = (|| {
-                    if !job_rx.is_empty() {
-                        current_block = job_rx.blocking_recv()?;
-                    }
-                    current_block.timestamp = chrono::Utc::now().timestamp() as u64;
-
-                    current_block.nonce = rng.random();
-                    current_block.meta.hash = Some(Hash::new(&current_block.get_hashing_buf()?));
-                    let mut removed_txs = false;
-                    // 10s expiration margin
-                    current_block.transactions.retain(|tx| {
-                        let expired = tx.timestamp + EXPIRATION_TIME + 10
-                            < chrono::Utc::now().timestamp() as u64;
-                        if expired {
-                            removed_txs = true;
-                        }
-                        !expired
-                    });
-
-                    if removed_txs {
-                        current_block.meta.merkle_tree_root = MerkleTree::build(
-                            &current_block
-                                .transactions
-                                .iter()
-                                .map(|tx| tx.transaction_id.unwrap())
-                                .collect::<Vec<TransactionId>>(),
-                        )
-                        .root_hash();
-                        current_block.meta.address_inclusion_filter =
-                            AddressInclusionFilter::create_filter(&current_block.transactions)?;
-                    }
+    if let Some(watchdog_timeout) = systemd::watchdog_timeout()
+        && Duration::from_secs(stats_interval_secs) * 2 > watchdog_timeout
+    {
+        warn!(
+            "stats.interval_secs is {stats_interval_secs}s, watchdog pings go out that often but systemd's WatchdogSec is {}s, keep the interval under half of it",
+            watchdog_timeout.as_secs_f64()
+        );
+    }
+    // Argon2 allocation and cold caches make the first seconds unrepresentative
+    let warmup = Duration::from_secs(miner_config.stats.warmup_secs);
 
-                    if BigUint::from_bytes_be(&current_block.meta.block_pow_difficulty)
-                        > BigUint::from_bytes_be(&*current_block.meta.hash.unwrap())
-                    {
-                        println!(
-                            "[THREAD {i}] Found block {}",
-                            current_block.meta.hash.unwrap().dump_base36()
-                        );
-                        submission_tx.blocking_send(current_block.clone())?;
-                    }
-                    hash_counter.fetch_add(1, Ordering::Relaxed);
+    let ema_alpha = miner_config.stats.ema_alpha;
+    if !(ema_alpha > 0.0 && ema_alpha <= 1.0) {
+        return Err(anyhow!(
+            "stats.ema_alpha must be in (0, 1], got {}",
+            ema_alpha
+        ));
+    }
+    let mut stats_format = StatsFormat::parse(&miner_config.stats.format)?;
+    if tui && stats_format == StatsFormat::Json {
+        // JSON lines are printed straight to stdout, right over the dashboard
+        warn!(
+            "stats.format json is replaced by human readable stats in the dashboard's log with --tui"
+        );
+        stats_format = StatsFormat::Human;
+    }
+    hash_format::init(HashFormat::parse(&miner_config.miner.hash_format)?);
+    // Appended to across runs, the header only goes into a new (or empty) file
+    let mut stats_csv = miner_config
+        .stats
+        .csv_path
+        .map(|path| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| anyhow!("Could not open stats.csv_path {}: {}", path.display(), e))?;
+            if file.metadata()?.len() == 0 {
+                file.write_all(b"unix_ts,hashes_per_second,total_hashes,blocks_found\n")?;
+            }
+            Ok::<_, anyhow::Error>(file)
+        })
+        .transpose()?;
+    let metrics_listen = miner_config
+        .metrics
+        .listen
+        .map(|listen| {
+            listen
+                .parse::<SocketAddr>()
+                .map_err(|e| anyhow!("Invalid metrics.listen \"{}\": {}", listen, e))
+        })
+        .transpose()?;
+    let alert = miner_config.alert;
+    if alert.webhook && miner_config.notify.webhook_url.is_none() {
+        return Err(anyhow!(
+            "alert.webhook is set, but there is no notify.webhook_url to send alerts to"
+        ));
+    }
+    let mut hash_rate_alert = match alert.min_hashrate {
+        Some(min_hashrate) if min_hashrate.is_nan() || min_hashrate <= 0.0 => {
+            return Err(anyhow!(
+                "alert.min_hashrate must be above 0, got {}",
+                min_hashrate
+            ));
+        }
+        Some(min_hashrate) => Some(HashRateAlert::new(
+            min_hashrate,
+            alert.min_hashrate_intervals,
+            alert.webhook,
+        )),
+        None => None,
+    };
+    let mut no_block_alert = match alert.no_block_secs {
+        Some(0) => return Err(anyhow!("alert.no_block_secs must be at least 1 second")),
+        // A pool finds the blocks, the shares it accepts are all this miner sees
+        Some(_) if pool_address.is_some() => {
+            warn!("alert.no_block_secs only applies to solo mining, ignoring it");
+            None
+        }
+        Some(no_block_secs) => Some(NoBlockAlert::new(
+            no_block_secs,
+            chrono::Utc::now().timestamp() as u64,
+            alert.webhook,
+        )),
+        None => None,
+    };
+    let notifier = Arc::new(BlockNotifier {
+        webhook: miner_config
+            .notify
+            .webhook_url
+            .map(|url| Webhook::parse(&url))
+            .transpose()?,
+        block_log_dir: miner_config.miner.block_log_dir,
+    });
 
-                    Ok::<(), anyhow::Error>(())
-                })() {
-                    println!("[THREAD {i}] Mining error: {e}")
+    let payout_addresses = miner_publics_base36
+        .iter()
+        .map(|public| {
+            if public == PUBLIC_PLACEHOLDER {
+                return Err(anyhow!(
+                    "The {} field in {} still holds the placeholder {}, please replace it with your real wallet address",
+                    payout_field,
+                    config_path,
+                    PUBLIC_PLACEHOLDER
+                ));
+            }
+            Public::new_from_base36(public).ok_or_else(|| {
+                anyhow!(
+                    "Invalid miner public address \"{}\", check the {} field in {}",
+                    public,
+                    payout_field,
+                    config_path
+                )
+            })
+        })
+        .collect::<Result<Vec<Public>, _>>()
+        .map_err(|e| ExitReason::InvalidMinerKey.wrap(e))?;
+    let payouts = match &payout_entries {
+        Some(entries) => {
+            let weights = entries.iter().map(|entry| entry.weight);
+            Payouts::weighted(payout_addresses.into_iter().zip(weights).collect())?
+        }
+        None => Payouts::round_robin(payout_addresses),
+    };
+    // Shares are reported under the (first) wallet address unless a worker name is configured
+    let pool_worker = miner_config
+        .pool
+        .worker
+        .unwrap_or_else(|| miner_publics_base36[0].clone());
+    configured.store(true, Ordering::Relaxed);
+
+    if validate {
+        match &pool_address {
+            Some(pool_address) => {
+                timeout(connect_timeout, pool_address.connect_tcp())
+                    .await
+                    .map_err(|_| anyhow!("timed out after {}s", connect_timeout_secs))
+                    .and_then(|connected| Ok(connected?))
+                    .map_err(|e| {
+                        ExitReason::NodeUnreachable
+                            .wrap(anyhow!("Could not reach pool {pool_address}: {e}"))
+                    })?;
+                info!("Pool {pool_address} is reachable");
+            }
+            None => {
+                for node in parse_nodes(&node_addresses)? {
+                    let height = async {
+                        let client = timeout(connect_timeout, node.connect(tls.as_ref()))
+                            .await
+                            .map_err(|_| anyhow!("timed out after {}s", connect_timeout_secs))??;
+                        Ok::<_, anyhow::Error>(
+                            timeout(
+                                Duration::from_secs(request_timeout_secs),
+                                client.get_height(),
+                            )
+                            .await
+                            .map_err(|_| {
+                                anyhow!("get_height timed out after {}s", request_timeout_secs)
+                            })??,
+                        )
+                    }
+                    .await
+                    .map_err(|e| {
+                        ExitReason::NodeUnreachable
+                            .wrap(anyhow!("Could not reach node {node}: {e}"))
+                    })?;
+                    info!("Node {node} is reachable, at height {height}");
                 }
             }
-        });
+        }
+        info!(
+            "{} is valid: {} threads, {} payout address{}, mining {}",
+            config_path,
+            thread_count,
+            miner_publics_base36.len(),
+            if miner_publics_base36.len() == 1 {
+                ""
+            } else {
+                "es"
+            },
+            match &pool_address {
+                Some(pool_address) => format!("for pool {pool_address}"),
+                None => format!("solo on {} node(s)", node_addresses.len()),
+            }
+        );
+        return Ok(());
     }
 
-    let _job_task = {
-        let client = client.clone();
+    // A task for block submissions, submitted via MPSC and a new block transmitted to all mining threads via a broadcast.
+    let (submission_tx, submission_rx) = mpsc::channel::<FoundBlock>(1);
 
-        tokio::spawn(async move {
-            async fn get_current_mempool(
-                client: &Client,
-            ) -> Result<Vec<Transaction>, BlockchainDataProviderError> {
-                let mut mempool = slice_vec(
-                    &client.get_mempool().await?,
-                    0,
-                    MAX_TRANSACTIONS_PER_BLOCK - 1,
-                )
-                .to_vec();
-                mempool.retain(|tx| {
-                    tx.timestamp + 5 < EXPIRATION_TIME + chrono::Utc::now().timestamp() as u64
-                }); // Add a 5s anti expiration buffer
-                Ok(mempool)
-            }
+    let (job_tx, _) = broadcast::channel::<Block>(64);
 
-            let is_refreshing = Arc::new(RwLock::new(false));
-            // We don't really care about what the event is because, it always requires recomputing the block
-            let refresh_block = move || {
-                let client = client.clone();
-                let job_tx = job_tx.clone();
-                let is_refreshing = is_refreshing.clone();
-                tokio::spawn(async move {
-                    if *is_refreshing.read().await {
-                        return;
-                    }
-                    *is_refreshing.write().await = true;
-                    if let Err(e) = async move {
-                        let block =
-                            build_block(&*client, &get_current_mempool(&*client).await?, miner_pub)
-                                .await?;
+    let metrics = Arc::new(Metrics::default());
+    // Only a pool hands out an easier target than the template's own, so it stays zero when solo mining
+    let share_target = Arc::new(ArcSwap::from_pointee(BigUint::ZERO));
+    if let Some(listen) = metrics_listen {
+        let listener = TcpListener::bind(listen).await?;
+        info!("Serving metrics on http://{listen}/metrics");
+        tokio::spawn(metrics::serve(listener, metrics.clone()).instrument(info_span!("metrics")));
+    }
 
-                        job_tx.send(block)?;
+    // Set on Ctrl-C, every loop checks it and winds down on its own
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let stats_stop = Arc::new(Notify::new());
+    // Set by a SIGHUP, the stats task clears its hash rate history on the next interval
+    let stats_reset = Arc::new(AtomicBool::new(false));
 
-                        Ok::<(), anyhow::Error>(())
-                    }
-                    .await
-                    {
-                        println!("[JOB] Error {e}");
+    // Bumped whenever the current template goes stale (refreshed or a block got accepted), threads reload on change
+    let epoch = Arc::new(AtomicU64::new(0));
+    // Asks the job task for a new template right away
+    let refresh_request = Arc::new(Notify::new());
+
+    // Stdin commands, only read from a terminal
+    let paused = Arc::new(AtomicBool::new(false));
+    let quit = Arc::new(Notify::new());
+
+    let shared = MinerShared {
+        submission_tx,
+        shutdown: shutdown.clone(),
+        epoch: epoch.clone(),
+        share_target: share_target.clone(),
+        settings: miner_settings,
+        pow,
+        metrics: metrics.clone(),
+        paused: paused.clone(),
+    };
+
+    // Create mining threads, they hold the only submission senders so the channel closes once they are shut down
+    let mining = MiningThreads::spawn(thread_count, &job_tx, shared);
+    // Ready for systemd once the first template goes out, from the node or the pool
+    let mut first_job = job_tx.subscribe();
+    tokio::spawn(async move {
+        if !matches!(
+            first_job.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ) {
+            systemd::ready();
+        }
+    });
+    // One counter per thread, so a stalling thread shows up in the stats
+    let hash_counters = mining.hash_counters.clone();
+    let mining = Arc::new(std::sync::Mutex::new(mining));
+
+    let hash_rate_task = {
+        let metrics = metrics.clone();
+        let stats_stop = stats_stop.clone();
+        let stats_reset = stats_reset.clone();
+        let notifier = notifier.clone();
+
+        tokio::spawn(
+            async move {
+                let start = Instant::now();
+                let mut total_hashes = 0u64;
+                // Seeded with the first interval's rate
+                let mut ema_hash_rate: Option<f64> = None;
+                let mut history = RateHistory::new(HASH_RATE_SAMPLES);
+                let mut hash_errors = 0u64;
+                let mut warming_up = !warmup.is_zero();
+                // Keeps reporting while shutdown drains pending submissions, main stops it once they are done
+                loop {
+                    let last = chrono::Utc::now().timestamp_millis() as f64;
+                    tokio::select! {
+                        _ = sleep(Duration::from_secs(stats_interval_secs)) => {}
+                        _ = stats_stop.notified() => break,
                     }
-                    *is_refreshing.write().await = false;
-                });
-            };
+                    systemd::watchdog();
+                    // A panicking report costs one interval, the stats carry on with the next
+                    let report = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let thread_hashes: Vec<u64> = hash_counters
+                            .load()
+                            .iter()
+                            .map(|counter| counter.swap(0, Ordering::Relaxed))
+                            .collect();
+                        // Hashes from the warm-up are dropped, the interval that ends it included
+                        if warming_up {
+                            if start.elapsed() >= warmup {
+                                warming_up = false;
+                                info!("Warm-up done after {}s, hash rate statistics start now", start.elapsed().as_secs());
+                            } else {
+                                info!("Warming up...");
+                            }
+                            return;
+                        }
+                        let hashes: u64 = thread_hashes.iter().sum();
+                        total_hashes += hashes;
+                        let delta = chrono::Utc::now().timestamp_millis() as f64 - last;
+                        let hash_rate = (hashes as f64 / delta) * 1000f64;
+                        metrics.set_hash_rate(hash_rate);
+                        let ema = match ema_hash_rate {
+                            Some(ema) => ema_alpha * hash_rate + (1.0 - ema_alpha) * ema,
+                            None => hash_rate,
+                        };
+                        ema_hash_rate = Some(ema);
+                        metrics.set_ema_hash_rate(ema);
+                        if stats_reset.swap(false, Ordering::Relaxed) {
+                            info!("Hash rate peak, min and median reset");
+                            history.clear();
+                        }
+                        history.push(hash_rate);
+                        let total_hash_errors = metrics.hash_errors.load(Ordering::Relaxed);
+                        if total_hash_errors > hash_errors {
+                            warn!(
+                                "{} hashes failed in the last interval, the latest with: {}",
+                                total_hash_errors - hash_errors,
+                                lock(&metrics.last_hash_error)
+                                    .as_deref()
+                                    .unwrap_or("unknown error")
+                            );
+                        }
+                        hash_errors = total_hash_errors;
+                        if let Some(hash_rate_alert) = &mut hash_rate_alert {
+                            hash_rate_alert.update(ema, &notifier);
+                        }
+                        let (peak, min, median) = (history.peak(), history.min(), history.median());
+                        let thread_rates: Vec<f64> = thread_hashes
+                            .iter()
+                            .map(|hashes| (*hashes as f64 / delta) * 1000f64)
+                            .collect();
+                        *lock(&metrics.thread_hash_rates) = thread_rates.clone();
 
-            // Initial block refresh
-            refresh_block();
+                        if let Some(csv) = &mut stats_csv
+                            && let Err(e) = writeln!(
+                                csv,
+                                "{},{},{},{}",
+                                chrono::Utc::now().timestamp(),
+                                hash_rate,
+                                total_hashes,
+                                metrics.blocks_found.load(Ordering::Relaxed)
+                            )
+                            .and_then(|_| csv.flush())
+                        {
+                            warn!("Failed to write stats row: {e}");
+                        }
 
-            if let Err(e) = event_client
-                .convert_to_event_listener(|_event| {
-                    refresh_block();
-                })
-                .await
-            {
-                println!("[JOB] Error: {:?}", e);
+                        let block_hashes = block_hash_summary(&lock(&metrics.block_hashes));
+                        let node_latency = metrics.node_latency.take_interval();
+                        let eta_secs = expected_secs_to_block(ema, metrics.difficulty());
+                        let network_rate =
+                            network_hash_rate(metrics.block_target(), target_block_time_secs);
+                        metrics.set_network_hash_rate(network_rate.unwrap_or(0.0));
+                        if let Some(no_block_alert) = &mut no_block_alert {
+                            let last_block = metrics.last_block_time.load(Ordering::Relaxed);
+                            no_block_alert.update(
+                                chrono::Utc::now().timestamp() as u64,
+                                (last_block > 0).then_some(last_block),
+                                eta_secs,
+                                &notifier,
+                            );
+                        }
+
+                        match stats_format {
+                            StatsFormat::Human => {
+                                let (display_rate, units) = format_hash_rate(hash_rate);
+                                let (display_ema, ema_units) = format_hash_rate(ema);
+                                let (display_total, total_units) = format_hash_count(total_hashes);
+                                let (display_peak, peak_units) = format_hash_rate(peak);
+                                let (display_min, min_units) = format_hash_rate(min);
+                                let (display_median, median_units) = format_hash_rate(median);
+                                // Colored output pads the rates, so they keep their column from line to line
+                                let (width, unit_width) = if style::enabled() { (7, 4) } else { (0, 0) };
+                                info!(
+                                    "{}",
+                                    style::dim(format!(
+                                        "Hash rate: {:>width$.2} {:<unit_width$} (ema {:>width$.2} {:<unit_width$}, peak {:.2} {}, min {:.2} {}, median {:.2} {}), total: {:.2} {} over {}, blocks: {} accepted, {} rejected, {} errors, ETA {}",
+                                        display_rate,
+                                        units,
+                                        display_ema,
+                                        ema_units,
+                                        display_peak,
+                                        peak_units,
+                                        display_min,
+                                        min_units,
+                                        display_median,
+                                        median_units,
+                                        display_total,
+                                        total_units,
+                                        format_uptime(start.elapsed()),
+                                        metrics.blocks_found.load(Ordering::Relaxed),
+                                        metrics.blocks_rejected.load(Ordering::Relaxed),
+                                        metrics.submit_errors.load(Ordering::Relaxed),
+                                        match eta_secs {
+                                            Some(eta_secs) =>
+                                                format!("~{} at current hashrate", format_eta(eta_secs)),
+                                            None => "unknown".to_string(),
+                                        }
+                                    ))
+                                );
+                                if let Some(network_rate) = network_rate {
+                                    let (display_network, network_units) =
+                                        format_hash_rate(network_rate);
+                                    info!(
+                                        "{}",
+                                        style::dim(format!(
+                                            "Network hash rate: ~{:.2} {} ({:.4}% ours)",
+                                            display_network,
+                                            network_units,
+                                            ema / network_rate * 100.0
+                                        ))
+                                    );
+                                }
+                                if let Some((min, median, max)) = block_hashes {
+                                    info!(
+                                        "{}",
+                                        style::dim(format!(
+                                            "Hashes per block: min {min}, median {median}, max {max} over {} blocks",
+                                            lock(&metrics.block_hashes).len()
+                                        ))
+                                    );
+                                }
+                                if !node_latency.is_empty() {
+                                    info!(
+                                        "{}",
+                                        style::dim(format!(
+                                            "Node latency: {}",
+                                            node_latency
+                                                .iter()
+                                                .map(|(request, secs)| format!("{request} {:.1}ms", secs * 1000.0))
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                        ))
+                                    );
+                                }
+                                for (i, thread_rate) in thread_rates.iter().enumerate() {
+                                    let (display_rate, units) = format_hash_rate(*thread_rate);
+                                    info!(
+                                        thread = i,
+                                        "{}",
+                                        style::dim(format!("{:>width$.2} {}", display_rate, units))
+                                    );
+                                }
+                            }
+                            // Printed raw rather than logged, so every line stays parseable JSON
+                            StatsFormat::Json => println!(
+                                "{}",
+                                serde_json::json!({
+                                    "timestamp": chrono::Utc::now().timestamp(),
+                                    "hashes_per_second": hash_rate,
+                                    "ema_hashes_per_second": ema,
+                                    "peak_hashes_per_second": peak,
+                                    "min_hashes_per_second": min,
+                                    "median_hashes_per_second": median,
+                                    "total_hashes": total_hashes,
+                                    "uptime_secs": start.elapsed().as_secs(),
+                                    "height": metrics.height(),
+                                    "blocks_found": metrics.blocks_found.load(Ordering::Relaxed),
+                                    "blocks_rejected": metrics.blocks_rejected.load(Ordering::Relaxed),
+                                    "blocks_stale": metrics.blocks_stale.load(Ordering::Relaxed),
+                                    "blocks_duplicate": metrics.blocks_duplicate.load(Ordering::Relaxed),
+                                    "submit_errors": metrics.submit_errors.load(Ordering::Relaxed),
+                                    "thread_restarts": metrics.thread_restarts.load(Ordering::Relaxed),
+                                    "hash_errors": hash_errors,
+                                    "eta_secs": eta_secs,
+                                    "network_hashes_per_second": network_rate,
+                                    "hashes_per_block": block_hashes.map(|(min, median, max)| serde_json::json!({
+                                        "min": min,
+                                        "median": median,
+                                        "max": max,
+                                    })),
+                                    "node_latency_ms": node_latency
+                                        .iter()
+                                        .map(|(request, secs)| (request.to_string(), serde_json::json!(secs * 1000.0)))
+                                        .collect::<serde_json::Map<_, _>>(),
+                                    "threads": thread_rates,
+                                })
+                            ),
+                        }
+                    }));
+                    if let Err(payload) = report {
+                        metrics.stats_panics.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "Stats report panicked ({}), carrying on with the next interval",
+                            panic_reason(&*payload)
+                        );
+                    }
+                }
+
+                total_hashes += hash_counters
+                    .load()
+                    .iter()
+                    .map(|counter| counter.swap(0, Ordering::Relaxed))
+                    .sum::<u64>();
+                info!(
+                    "Session summary: {} hashes over {}s, {} blocks found earning {} SNAP",
+                    total_hashes,
+                    start.elapsed().as_secs(),
+                    metrics.blocks_found.load(Ordering::Relaxed),
+                    to_snap(metrics.rewards_earned.load(Ordering::Relaxed))
+                );
             }
-        })
+            .instrument(info_span!("stats")),
+        )
     };
-
-    let _hash_rate_task = tokio::spawn(async move {
-        loop {
-            if let Err(e) = async {
-                let last = chrono::Utc::now().timestamp_millis() as f64;
-                sleep(Duration::from_secs(3)).await;
-
-                let hashes = hash_counter.swap(0, Ordering::Relaxed) as f64;
-                let delta = chrono::Utc::now().timestamp_millis() as f64 - last;
-                let (display_rate, units) = format_hash_rate((hashes / delta) * 1000f64);
-                println!("[STATUS] Hash rate: {} {}", display_rate, units);
-                Ok::<(), anyhow::Error>(())
+    // Node list the job task reconnects to, swapped by a config reload which then asks for a reconnect
+    // Timed out node requests ask for one too
+    let nodes = Arc::new(ArcSwap::from_pointee(parse_nodes(&node_addresses)?));
+    let reconnect = Arc::new(Notify::new());
+    #[cfg(unix)]
+    tokio::spawn(
+        reload::Reload {
+            config_path: config_path.to_string(),
+            loaded: loaded_config,
+            thread_count_override,
+            force,
+            memory_cost: argon2.memory_cost,
+            node_override,
+            pool_mining: pool_address.is_some(),
+            mining: mining.clone(),
+            nodes: nodes.clone(),
+            reconnect: reconnect.clone(),
+            refresh_request: refresh_request.clone(),
+            stats_reset: stats_reset.clone(),
+        }
+        .run()
+        .instrument(info_span!("reload")),
+    );
+    // Both want the terminal's input, the dashboard takes its keys in raw mode
+    let tui_stop = Arc::new(AtomicBool::new(false));
+    let dashboard = if tui {
+        Some(
+            Dashboard {
+                metrics: metrics.clone(),
+                log: tui_log,
+                quit: quit.clone(),
+                stop: tui_stop.clone(),
             }
-            .await
-            {
-                println!("[STATUS] Error: {:?}", e);
+            .spawn(),
+        )
+    } else {
+        control::Control {
+            metrics: metrics.clone(),
+            mining: mining.clone(),
+            memory_cost: argon2.memory_cost,
+            force,
+            refresh_request: refresh_request.clone(),
+            paused,
+            quit: quit.clone(),
+        }
+        .spawn();
+        None
+    };
+    let (job_task, submission_task) = match pool_address {
+        Some(address) => {
+            let pool = Arc::new(PoolClient::new(
+                address.clone(),
+                connect_timeout,
+                pool_worker,
+                share_target,
+            ));
+            info!("Mining for pool {address}");
+            if show_balance {
+                warn!("miner.show_balance needs a node connection, ignored while pool mining");
+            }
+            if broadcast_submit {
+                warn!("node.broadcast_submit needs a node connection, ignored while pool mining");
             }
+            if !min_submit_interval.is_zero() {
+                warn!(
+                    "node.min_submit_interval_ms only spaces node submissions, ignored while pool mining"
+                );
+            }
+            if empty_blocks {
+                warn!(
+                    "miner.empty_blocks only applies to templates built from a node, ignored while pool mining"
+                );
+            }
+            let job_task = {
+                let pool = pool.clone();
+                let epoch = epoch.clone();
+                let metrics = metrics.clone();
+                let refresh_request = refresh_request.clone();
+                tokio::spawn(
+                    async move {
+                        // The pool pushes its own templates, a requested refresh just re-sends the latest one
+                        let requested = async {
+                            loop {
+                                refresh_request.notified().await;
+                                if let Err(e) =
+                                    work::refresh(&*pool, &job_tx, &epoch, &metrics).await
+                                {
+                                    error!("Error {e}");
+                                }
+                            }
+                        };
+                        tokio::join!(
+                            pool.run(job_tx.clone(), epoch.clone(), metrics.clone()),
+                            requested
+                        );
+                    }
+                    .instrument(info_span!("pool")),
+                )
+            };
+            let submission_task = tokio::spawn(
+                async move {
+                    work::submit_found(
+                        &*pool,
+                        submission_rx,
+                        dry_run,
+                        &epoch,
+                        &refresh_request,
+                        &metrics,
+                        &notifier,
+                    )
+                    .await
+                }
+                .instrument(info_span!("submissions")),
+            );
+            (job_task, submission_task)
         }
-    });
-    let submission_task = tokio::spawn(async move {
-        loop {
-            if let Err(e) = async {
-                let candidate = submission_rx.recv().await;
-                if let Some(candidate) = candidate {
-                    client.submit_block(candidate).await??;
-                    println!(
-                        "[SUBMISSIONS] Block validated! Miner rewarded {} SNAP",
-                        to_snap(get_block_reward(
-                            client.get_height().await?.saturating_sub(1)
-                        ))
-                    );
-                } else {
-                    eprint!("[SUBMISSIONS] All miner threads died!");
+        None => {
+            let (node, client, event_client) =
+                connect_first_available(&nodes.load(), connect_timeout, tls.as_ref())
+                    .await
+                    .ok_or_else(|| {
+                        ExitReason::NodeUnreachable
+                            .wrap(anyhow!("Could not connect to any configured node"))
+                    })?;
+            info!("Connected to node {node}");
+            let mut source = NodeSource::new(
+                client,
+                node.clone(),
+                payouts,
+                submit_retries,
+                Duration::from_secs(request_timeout_secs),
+                reconnect.clone(),
+            )
+            .with_selector(if empty_blocks {
+                Box::new(NoTxs)
+            } else {
+                Box::new(AtMost {
+                    max: max_txs_per_block,
+                    inner: Box::new(AllTxs),
+                })
+            })
+            .throttle_submits(min_submit_interval)
+            .record_latency(metrics.clone(), slow_request);
+            if broadcast_submit {
+                if nodes.load().len() < 2 {
+                    warn!("node.broadcast_submit is set, but there is only one node to submit to");
                 }
-
-                Ok::<(), anyhow::Error>(())
+                source = source.broadcast_to(Broadcast {
+                    nodes: nodes.clone(),
+                    connect_timeout,
+                    tls: tls.clone(),
+                });
             }
-            .await
-            {
-                println!("[SUBMISSIONS] Error: {:?}", e);
+            let source = Arc::new(source);
+            metrics.node_breaker.set_threshold(breaker_failures);
+            // The threads sit idle until this first template arrives, so a node that can't build one aborts startup
+            work::refresh(&*source, &job_tx, &epoch, &metrics)
+                .await
+                .map_err(|e| {
+                    ExitReason::NodeUnreachable
+                        .wrap(anyhow!("Could not fetch the initial block template: {e}"))
+                })?;
+            if show_balance {
+                let source = source.clone();
+                tokio::spawn(
+                    async move {
+                        source
+                            .show_balances(Duration::from_secs(balance_interval_secs))
+                            .await
+                    }
+                    .instrument(info_span!("balance")),
+                );
             }
+
+            let job_task = {
+                let source = source.clone();
+                let refresh_source = source.clone();
+                let shutdown = shutdown.clone();
+                let metrics = metrics.clone();
+                let epoch = epoch.clone();
+                let pause_epoch = epoch.clone();
+                let breaker_metrics = metrics.clone();
+                let refresh_request = refresh_request.clone();
+
+                tokio::spawn(
+                    async move {
+                        let is_refreshing = Arc::new(RwLock::new(false));
+                        // Set while the node isn't synced, no templates go out to the threads then
+                        let paused = Arc::new(AtomicBool::new(false));
+                        let refresh_paused = paused.clone();
+                        // We don't really care about what the event is because, it always requires recomputing the block
+                        let refresh_block = move || {
+                            let source = refresh_source.clone();
+                            let job_tx = job_tx.clone();
+                            let is_refreshing = is_refreshing.clone();
+                            let shutdown = shutdown.clone();
+                            let metrics = metrics.clone();
+                            let epoch = epoch.clone();
+                            let paused = refresh_paused.clone();
+                            tokio::spawn(
+                                async move {
+                                    if shutdown.load(Ordering::Relaxed)
+                                        || paused.load(Ordering::Relaxed)
+                                        || metrics.node_breaker.is_open()
+                                        || *is_refreshing.read().await
+                                    {
+                                        return;
+                                    }
+                                    *is_refreshing.write().await = true;
+                                    match work::refresh(&*source, &job_tx, &epoch, &metrics).await {
+                                        Ok(()) => metrics.node_breaker.succeeded(),
+                                        Err(e) => {
+                                            error!("Error {e}");
+                                            metrics.node_breaker.failed(&epoch);
+                                        }
+                                    }
+                                    *is_refreshing.write().await = false;
+                                }
+                                .in_current_span(),
+                            );
+                        };
+
+                        // The event stream ending means the node connection dropped, so reconnect both clients
+                        // A reloaded node list or a timed out request reconnects the same way, starting over from the first node
+                        let events = async {
+                            let mut event_client = event_client;
+                            let mut active_node = node;
+                            loop {
+                                tokio::select! {
+                                    result = event_client.convert_to_event_listener(|_event| {
+                                        refresh_block();
+                                    }) => {
+                                        if let Err(e) = result {
+                                            error!("Error: {:?}", e);
+                                        }
+                                        warn!("Lost connection to node {active_node}, reconnecting");
+                                    }
+                                    _ = reconnect.notified() => info!("Dropping the connection to node {active_node}, reconnecting"),
+                                }
+                                let (node, new_client, new_event_client) =
+                                    connect_with_backoff(&nodes.load(), connect_timeout, tls.as_ref()).await;
+                                if node != active_node {
+                                    warn!("Failed over from node {active_node} to {node}");
+                                }
+                                info!("Connected to node {node}");
+                                active_node = node;
+                                source.connection.store(Arc::new(Connection::new(new_client)));
+                                source.node.store(Arc::new(active_node.clone()));
+                                event_client = new_event_client;
+                                refresh_block();
+                            }
+                        };
+                        // Also refresh on a timer, so the template keeps moving even if the event stream drops
+                        let periodic = async {
+                            loop {
+                                sleep(Duration::from_secs(refresh_interval_secs)).await;
+                                refresh_block();
+                            }
+                        };
+                        // Asked for when the threads need a template, so one goes out even if nothing changed
+                        let requested =
+                            work::answer_refresh_requests(&*source, &refresh_request, &refresh_block);
+                        // A node without peers can't know the network's tip, blocks built on its own would be orphaned
+                        let sync_check = async {
+                            if required_peers == 0 {
+                                return;
+                            }
+                            loop {
+                                match source.peer_count().await {
+                                    Ok(peers) if peers < required_peers => {
+                                        if paused.swap(true, Ordering::Relaxed) {
+                                            info!("Waiting for peers, {} reports {peers} of {required_peers}", source.node.load());
+                                        } else {
+                                            if peers == 0 {
+                                                warn!("Node not synced, pausing: {} reports no peers", source.node.load());
+                                            } else {
+                                                warn!("Too few peers, pausing: {} reports {peers}, node.min_peers is {required_peers}", source.node.load());
+                                            }
+                                            // With the epoch bumped and no template queued the threads wait for the next one
+                                            pause_epoch.fetch_add(1, Ordering::Release);
+                                        }
+                                    }
+                                    Ok(peers) if paused.swap(false, Ordering::Relaxed) => {
+                                        info!("Node synced with {peers} peers, resuming");
+                                        source.forget_template();
+                                        refresh_block();
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => warn!("Failed to check the node's peers: {e}"),
+                                }
+                                sleep(SYNC_CHECK_INTERVAL).await;
+                            }
+                        };
+                        // No refreshes go out while the breaker is open, only a probe now and then until the node answers
+                        let breaker = async {
+                            loop {
+                                sleep(BREAKER_PROBE_INTERVAL).await;
+                                if !breaker_metrics.node_breaker.is_open() {
+                                    continue;
+                                }
+                                match source.probe().await {
+                                    Ok(()) => {
+                                        info!("Node {} answers again, resuming", source.node.load());
+                                        breaker_metrics.node_breaker.close();
+                                        source.forget_template();
+                                        refresh_block();
+                                    }
+                                    Err(e) => warn!("Node still failing, staying paused: {e}"),
+                                }
+                            }
+                        };
+                        tokio::join!(events, periodic, requested, sync_check, breaker);
+                    }
+                    .instrument(info_span!("job")),
+                )
+            };
+
+            let submission_task = tokio::spawn(
+                async move {
+                    work::submit_found(
+                        &*source,
+                        submission_rx,
+                        dry_run,
+                        &epoch,
+                        &refresh_request,
+                        &metrics,
+                        &notifier,
+                    )
+                    .await
+                }
+                .instrument(info_span!("submissions")),
+            );
+            (job_task, submission_task)
         }
-    });
+    };
+
+    #[cfg(unix)]
+    {
+        // systemd stops services with SIGTERM
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+            _ = quit.notified() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = quit.notified() => {}
+    }
+    // Handed back first, so everything from here on is logged to the terminal as usual
+    if let Some(dashboard) = dashboard {
+        tui_stop.store(true, Ordering::Relaxed);
+        let _ = tokio::task::spawn_blocking(move || dashboard.join()).await;
+    }
+    info!("Shutting down...");
+    systemd::stopping();
+    shutdown.store(true, Ordering::Relaxed);
+
+    // Dropping the job task closes the job channel, releasing threads still waiting on their first block
+    job_task.abort();
+    let mining_threads = lock(&mining).shutdown();
+    tokio::task::spawn_blocking(move || {
+        for mining_thread in mining_threads {
+            let _ = mining_thread.join();
+        }
+    })
+    .await?;
 
     submission_task.await?;
+    stats_stop.notify_one();
+    hash_rate_task.await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+
+    #[test]
+    fn uncommented_pool_keys_land_in_the_pool_section() {
+        let uncommented = DEFAULT_CONFIG
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(key)
+                    if key == "[pool]"
+                        || key.starts_with("url =")
+                        || key.starts_with("worker =") =>
+                {
+                    key
+                }
+                _ => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: settings::MinerConfig = Config::builder()
+            .add_source(File::from_str(&uncommented, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(
+            config.pool.url.as_deref(),
+            Some("stratum+tcp://127.0.0.1:4444")
+        );
+        assert_eq!(config.pool.worker.as_deref(), Some("rig1"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::{
+    Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+
+use num_bigint::BigUint;
+use snap_coin::economics::to_snap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{Instrument, warn};
+
+use crate::{breaker::CircuitBreaker, latency::NodeLatency};
+
+/// Counters and gauges shared between the mining, refresh, submission and stats tasks
+/// Floating point gauges are stored as their `f64` bit pattern
+#[derive(Default)]
+pub struct Metrics {
+    hash_rate: AtomicU64,
+    ema_hash_rate: AtomicU64,
+    /// Per thread rates of the last stats interval, by thread id
+    pub thread_hash_rates: Mutex<Vec<f64>>,
+    difficulty: AtomicU64,
+    /// Block pow target of the template being mined, the pool share target aside
+    block_target: AtomicU64,
+    network_hash_rate: AtomicU64,
+    /// Height of the template being mined plus one, zero while unknown
+    height: AtomicU64,
+    pub blocks_found: AtomicU64,
+    /// Nano the accepted blocks pay the miner, the dev fee aside
+    pub rewards_earned: AtomicU64,
+    /// Unix time of the last accepted block, zero before the first
+    pub last_block_time: AtomicU64,
+    /// Blocks the node turned down, most likely stale
+    pub blocks_rejected: AtomicU64,
+    /// The rejected blocks the chain had moved past
+    pub blocks_stale: AtomicU64,
+    /// Blocks a node had seen already, taken without adding anything to the chain
+    pub blocks_duplicate: AtomicU64,
+    /// Submissions that never got an answer, eg. network errors
+    pub submit_errors: AtomicU64,
+    pub submit_failures: AtomicU64,
+    pub shares_accepted: AtomicU64,
+    /// Mining threads restarted after a panic
+    pub thread_restarts: AtomicU64,
+    /// Stats reports cut short by a panic
+    pub stats_panics: AtomicU64,
+    /// Hashes the proof of work failed to compute, these aren't counted in the hash rate
+    pub hash_errors: AtomicU64,
+    /// Text of the latest hash error, for the stats task's periodic warning
+    pub last_hash_error: Mutex<Option<String>>,
+    /// Hashes the finding thread tried on its template for every block accepted this session
+    pub block_hashes: Mutex<Vec<u64>>,
+    pub node_latency: NodeLatency,
+    pub node_breaker: CircuitBreaker,
+}
+
+impl Metrics {
+    pub fn set_hash_rate(&self, hash_rate: f64) {
+        self.hash_rate.store(hash_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn hash_rate(&self) -> f64 {
+        f64::from_bits(self.hash_rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_ema_hash_rate(&self, ema_hash_rate: f64) {
+        self.ema_hash_rate
+            .store(ema_hash_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn ema_hash_rate(&self) -> f64 {
+        f64::from_bits(self.ema_hash_rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_difficulty(&self, difficulty: &BigUint) {
+        self.difficulty
+            .store(biguint_to_f64(difficulty).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn difficulty(&self) -> f64 {
+        f64::from_bits(self.difficulty.load(Ordering::Relaxed))
+    }
+
+    pub fn set_block_target(&self, block_target: &BigUint) {
+        self.block_target
+            .store(biguint_to_f64(block_target).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn block_target(&self) -> f64 {
+        f64::from_bits(self.block_target.load(Ordering::Relaxed))
+    }
+
+    pub fn set_network_hash_rate(&self, network_hash_rate: f64) {
+        self.network_hash_rate
+            .store(network_hash_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn network_hash_rate(&self) -> f64 {
+        f64::from_bits(self.network_hash_rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_height(&self, height: Option<u64>) {
+        self.height
+            .store(height.map_or(0, |height| height + 1), Ordering::Relaxed);
+    }
+
+    pub fn height(&self) -> Option<u64> {
+        self.height.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let counters = format!(
+            "# HELP snap_miner_hashes_per_second Hash rate over the last stats interval\n\
+             # TYPE snap_miner_hashes_per_second gauge\n\
+             snap_miner_hashes_per_second {}\n\
+             # HELP snap_miner_blocks_submitted_total Blocks accepted by the node\n\
+             # TYPE snap_miner_blocks_submitted_total counter\n\
+             snap_miner_blocks_submitted_total {}\n\
+             # HELP snap_miner_rewards_earned_snap_total SNAP the accepted blocks pay the miner, after the dev fee\n\
+             # TYPE snap_miner_rewards_earned_snap_total counter\n\
+             snap_miner_rewards_earned_snap_total {}\n\
+             # HELP snap_miner_blocks_rejected_total Blocks rejected by the node\n\
+             # TYPE snap_miner_blocks_rejected_total counter\n\
+             snap_miner_blocks_rejected_total {}\n\
+             # HELP snap_miner_blocks_stale_total Blocks rejected because the chain had moved past their template\n\
+             # TYPE snap_miner_blocks_stale_total counter\n\
+             snap_miner_blocks_stale_total {}\n\
+             # HELP snap_miner_blocks_duplicate_total Blocks the node had seen already, nothing was added to the chain\n\
+             # TYPE snap_miner_blocks_duplicate_total counter\n\
+             snap_miner_blocks_duplicate_total {}\n\
+             # HELP snap_miner_submit_errors_total Block or share submissions that failed to reach the node or pool\n\
+             # TYPE snap_miner_submit_errors_total counter\n\
+             snap_miner_submit_errors_total {}\n\
+             # HELP snap_miner_submit_failures_total Block or share submissions that failed or were rejected\n\
+             # TYPE snap_miner_submit_failures_total counter\n\
+             snap_miner_submit_failures_total {}\n\
+             # HELP snap_miner_shares_accepted_total Shares accepted by the pool\n\
+             # TYPE snap_miner_shares_accepted_total counter\n\
+             snap_miner_shares_accepted_total {}\n\
+             # HELP snap_miner_thread_restarts_total Mining threads restarted after a panic\n\
+             # TYPE snap_miner_thread_restarts_total counter\n\
+             snap_miner_thread_restarts_total {}\n\
+             # HELP snap_miner_stats_panics_total Stats reports that panicked, the stats task carries on with the next interval\n\
+             # TYPE snap_miner_stats_panics_total counter\n\
+             snap_miner_stats_panics_total {}\n\
+             # HELP snap_miner_hash_errors_total Hashes the proof of work failed to compute\n\
+             # TYPE snap_miner_hash_errors_total counter\n\
+             snap_miner_hash_errors_total {}\n\
+             # HELP snap_miner_difficulty Block pow target of the template being mined (higher is easier)\n\
+             # TYPE snap_miner_difficulty gauge\n\
+             snap_miner_difficulty {}\n\
+             # HELP snap_miner_network_hashes_per_second Network hash rate estimated from the block target and target block time\n\
+             # TYPE snap_miner_network_hashes_per_second gauge\n\
+             snap_miner_network_hashes_per_second {}\n\
+             # HELP snap_miner_node_breaker_open Whether mining is paused because the node kept failing (1) or not (0)\n\
+             # TYPE snap_miner_node_breaker_open gauge\n\
+             snap_miner_node_breaker_open {}\n\
+             # HELP snap_miner_node_breaker_trips_total Times mining was paused because the node kept failing\n\
+             # TYPE snap_miner_node_breaker_trips_total counter\n\
+             snap_miner_node_breaker_trips_total {}\n",
+            self.hash_rate(),
+            self.blocks_found.load(Ordering::Relaxed),
+            to_snap(self.rewards_earned.load(Ordering::Relaxed)),
+            self.blocks_rejected.load(Ordering::Relaxed),
+            self.blocks_stale.load(Ordering::Relaxed),
+            self.blocks_duplicate.load(Ordering::Relaxed),
+            self.submit_errors.load(Ordering::Relaxed),
+            self.submit_failures.load(Ordering::Relaxed),
+            self.shares_accepted.load(Ordering::Relaxed),
+            self.thread_restarts.load(Ordering::Relaxed),
+            self.stats_panics.load(Ordering::Relaxed),
+            self.hash_errors.load(Ordering::Relaxed),
+            self.difficulty(),
+            self.network_hash_rate(),
+            self.node_breaker.is_open() as u8,
+            self.node_breaker.trips.load(Ordering::Relaxed),
+        );
+        counters + &self.node_latency.render()
+    }
+}
+
+/// Lossy conversion of a (up to 256 bit) big integer to a float, good enough for a gauge
+pub fn biguint_to_f64(value: &BigUint) -> f64 {
+    value
+        .to_bytes_be()
+        .iter()
+        .fold(0f64, |acc, byte| acc * 256.0 + *byte as f64)
+}
+
+/// Serve `/metrics` over plain HTTP until the task is dropped
+pub async fn serve(listener: TcpListener, metrics: std::sync::Arc<Metrics>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let metrics = metrics.clone();
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = respond(stream, &metrics).await {
+                            warn!("Error: {e}");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+            Err(e) => warn!("Error: {e}"),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), anyhow::Error> {
+    // Only the request line matters, so a single read is enough
+    let mut request = [0u8; 1024];
+    let read = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,559 @@
+use std::{
+    any::Any,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use num_bigint::BigUint;
+use snap_coin::{
+    core::{
+        block::{Block, MAX_TRANSACTIONS_PER_BLOCK},
+        transaction::TransactionId,
+    },
+    crypto::{address_inclusion_filter::AddressInclusionFilter, merkle_tree::MerkleTree},
+    economics::EXPIRATION_TIME,
+};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{Level, debug, error, info, info_span, warn};
+
+use crate::{
+    hash_format,
+    hashing::{HashingTemplate, ProofOfWork},
+    lock::lock,
+    metrics::{Metrics, biguint_to_f64},
+    nonce::NonceSlice,
+};
+
+/// How often a paused thread checks whether it may carry on
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait for the next block template, skipping ahead to the newest one if several are queued
+/// Returns `None` once the job channel is closed
+fn next_job(job_rx: &mut broadcast::Receiver<Block>) -> Option<Block> {
+    let mut job = loop {
+        match job_rx.blocking_recv() {
+            Ok(job) => break job,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    };
+    loop {
+        match job_rx.try_recv() {
+            Ok(newer) => job = newer,
+            Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+            Err(_) => return Some(job),
+        }
+    }
+}
+
+/// The message a panic was raised with
+pub fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|reason| reason.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Per-thread mining knobs from the `[miner]` config section
+#[derive(Clone, Copy)]
+pub struct MinerSettings {
+    /// Hashes between checks for new work, expired transactions and shutdown
+    pub batch_size: u64,
+    /// Share of its time each thread spends hashing, the rest is slept off after every batch
+    pub max_cpu_percent: u32,
+    /// Bind thread `i` to core `i % cores`
+    pub pin_threads: bool,
+    /// Stamp every hash with the current time instead of keeping the template's creation time
+    pub refresh_timestamp: bool,
+    /// `--seed`, makes the nonces tried on a template reproducible (testing only)
+    pub seed: Option<u64>,
+}
+
+/// A block that beat its target, with where and how fast it was found
+pub struct FoundBlock {
+    pub block: Block,
+    pub thread_id: u64,
+    /// Time since the thread started hashing the template the block was found on
+    pub time_to_find: Duration,
+    /// Hashes the thread tried on that template, this one included
+    pub hashes_to_find: u64,
+}
+
+/// Channels and state every mining thread shares with the rest of the miner
+#[derive(Clone)]
+pub struct MinerShared {
+    pub submission_tx: mpsc::Sender<FoundBlock>,
+    pub shutdown: Arc<AtomicBool>,
+    pub epoch: Arc<AtomicU64>,
+    /// Extra target a hash is submitted under on top of the template's own, the share target when pool mining, zero otherwise
+    pub share_target: Arc<ArcSwap<BigUint>>,
+    pub settings: MinerSettings,
+    pub pow: Arc<dyn ProofOfWork>,
+    /// Only `thread_restarts` and the hash errors are touched by the mining threads
+    pub metrics: Arc<Metrics>,
+    /// Set by the `pause` command, threads idle between batches while it is
+    pub paused: Arc<AtomicBool>,
+}
+
+/// Re-check a solved block from scratch before it is submitted: its hash recomputed through the slow path, and its merkle root and size
+/// Catches a hashing template drifting out of sync with the block it was built from
+fn validate_locally(block: &Block, pow: &dyn ProofOfWork) -> Result<(), anyhow::Error> {
+    if block.transactions.len() > MAX_TRANSACTIONS_PER_BLOCK {
+        return Err(anyhow!(
+            "{} transactions, over the limit of {}",
+            block.transactions.len(),
+            MAX_TRANSACTIONS_PER_BLOCK
+        ));
+    }
+    let transaction_ids = block
+        .transactions
+        .iter()
+        .map(|tx| {
+            tx.transaction_id
+                .ok_or_else(|| anyhow!("Transaction without an id"))
+        })
+        .collect::<Result<Vec<TransactionId>, _>>()?;
+    if MerkleTree::build(&transaction_ids).root_hash() != block.meta.merkle_tree_root {
+        return Err(anyhow!("Merkle root does not match the transactions"));
+    }
+    if block.meta.hash != Some(pow.hash(&block.get_hashing_buf()?)?) {
+        return Err(anyhow!("Hash does not match the block's hashing buffer"));
+    }
+    Ok(())
+}
+
+/// Spawn `thread_count` mining threads on templates from `job_tx`, one hash counter each
+/// A thread that panics is restarted in place and picks up the next template, so one faulty hash doesn't cost its share of the hash rate
+pub fn spawn_mining_threads(
+    thread_count: i32,
+    job_tx: &broadcast::Sender<Block>,
+    hash_counters: &[Arc<AtomicU64>],
+    shared: &MinerShared,
+) -> Vec<thread::JoinHandle<()>> {
+    let cores = if shared.settings.pin_threads {
+        match core_affinity::get_core_ids() {
+            Some(cores) if !cores.is_empty() => Some(cores),
+            _ => {
+                warn!(
+                    "Thread pinning is not supported on this platform, threads are left unpinned"
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    (0..thread_count)
+        .map(|i| {
+            let mut job_rx = job_tx.subscribe();
+            let hash_counter = hash_counters[i as usize].clone();
+            let shared = shared.clone();
+            let core = cores.as_ref().map(|cores| cores[i as usize % cores.len()]);
+            if let Some(core) = core {
+                info!("Pinning thread {i} to core {}", core.id);
+            }
+            thread::spawn(move || {
+                let _span = info_span!("thread", id = i).entered();
+                if let Some(core) = core
+                    && !core_affinity::set_for_current(core)
+                {
+                    warn!("Failed to pin to core {}", core.id);
+                }
+                loop {
+                    let mining = panic::catch_unwind(AssertUnwindSafe(|| {
+                        mine_thread(
+                            i as u64,
+                            thread_count as u64,
+                            &mut job_rx,
+                            hash_counter.clone(),
+                            shared.clone(),
+                        )
+                    }));
+                    let Err(payload) = mining else {
+                        break;
+                    };
+                    if shared.shutdown.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let reason = panic_reason(&*payload);
+                    shared
+                        .metrics
+                        .thread_restarts
+                        .fetch_add(1, Ordering::Relaxed);
+                    error!("Mining thread panicked ({reason}), restarting");
+                }
+            })
+        })
+        .collect()
+}
+
+/// The running mining threads, respawned as a whole when the thread count changes since every nonce slice depends on it
+pub struct MiningThreads {
+    /// Weak, so the job channel still closes when the job task ends
+    job_tx: broadcast::WeakSender<Block>,
+    /// Handed to every generation of threads, `None` once shut down so the submission channel can close
+    shared: Option<MinerShared>,
+    /// One counter per running thread, replaced on every resize
+    pub hash_counters: Arc<ArcSwap<Vec<Arc<AtomicU64>>>>,
+    /// Stop flag of the running generation, it takes the place of `shared.shutdown`
+    stop: Arc<AtomicBool>,
+    /// Threads of the running generation and of retired ones still winding down
+    handles: Vec<thread::JoinHandle<()>>,
+    thread_count: i32,
+}
+
+impl MiningThreads {
+    pub fn spawn(
+        thread_count: i32,
+        job_tx: &broadcast::Sender<Block>,
+        shared: MinerShared,
+    ) -> Self {
+        let mut threads = MiningThreads {
+            job_tx: job_tx.downgrade(),
+            shared: Some(shared),
+            hash_counters: Arc::new(ArcSwap::from_pointee(vec![])),
+            stop: Arc::new(AtomicBool::new(false)),
+            handles: vec![],
+            thread_count: 0,
+        };
+        threads.start(thread_count, job_tx);
+        threads
+    }
+
+    pub fn thread_count(&self) -> i32 {
+        self.thread_count
+    }
+
+    fn start(&mut self, thread_count: i32, job_tx: &broadcast::Sender<Block>) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        // Counters are carried over by index, so the stats keep the hashes retiring threads do before they stop
+        let old_counters = self.hash_counters.load();
+        let hash_counters: Vec<Arc<AtomicU64>> = (0..thread_count as usize)
+            .map(|i| {
+                old_counters
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(AtomicU64::new(0)))
+            })
+            .collect();
+        self.stop = Arc::new(AtomicBool::new(false));
+        let shared = MinerShared {
+            shutdown: self.stop.clone(),
+            ..shared.clone()
+        };
+        self.handles.extend(spawn_mining_threads(
+            thread_count,
+            job_tx,
+            &hash_counters,
+            &shared,
+        ));
+        self.hash_counters.store(Arc::new(hash_counters));
+        self.thread_count = thread_count;
+    }
+
+    /// Stop the running threads and start `thread_count` fresh ones, which wait for the next template
+    pub fn resize(&mut self, thread_count: i32) {
+        let Some(job_tx) = self.job_tx.upgrade() else {
+            return;
+        };
+        self.stop.store(true, Ordering::Relaxed);
+        self.handles.retain(|handle| !handle.is_finished());
+        self.start(thread_count, &job_tx);
+    }
+
+    /// Stop every thread for good, returning them to be joined
+    pub fn shutdown(&mut self) -> Vec<thread::JoinHandle<()>> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.shared = None;
+        mem::take(&mut self.handles)
+    }
+}
+
+/// Hash templates from `job_rx` until `shutdown` is set, sending every block that beats its target to `submission_tx`
+/// Returns early if the job channel closes before a template arrives
+fn mine_thread(
+    thread_id: u64,
+    thread_count: u64,
+    job_rx: &mut broadcast::Receiver<Block>,
+    hash_counter: Arc<AtomicU64>,
+    shared: MinerShared,
+) {
+    let MinerShared {
+        submission_tx,
+        shutdown,
+        epoch,
+        share_target,
+        settings,
+        pow,
+        metrics,
+        paused,
+    } = shared;
+    // At startup wait for block thread to create a block, blocking on the channel rather than hashing a placeholder
+    info!("Starting miner, waiting for work");
+    let Some(mut current_block) = next_job(job_rx) else {
+        return;
+    };
+    info!("Received work, mining");
+    let mut template_epoch = epoch.load(Ordering::Acquire);
+    let mut template_start = Instant::now();
+    let mut template_hashes = 0u64;
+
+    let mut nonces = NonceSlice::new(thread_id, thread_count, settings.seed);
+    nonces.reset(&current_block);
+    // Rebuilt lazily whenever the block's transactions or meta change
+    let mut template: Option<HashingTemplate> = None;
+    // Set while whole batches fail to hash
+    let mut hashing_failed = false;
+    while !shutdown.load(Ordering::Relaxed) {
+        if paused.load(Ordering::Relaxed) {
+            thread::sleep(PAUSE_POLL_INTERVAL);
+            continue;
+        }
+        if let Err(e) = (|| {
+            // A bumped epoch with nothing queued means a block was just accepted, so wait for the next template
+            if !job_rx.is_empty() || epoch.load(Ordering::Acquire) != template_epoch {
+                let Some(job) = next_job(job_rx) else {
+                    return Ok(());
+                };
+                current_block = job;
+                template_epoch = epoch.load(Ordering::Acquire);
+                template_start = Instant::now();
+                template_hashes = 0;
+                nonces.reset(&current_block);
+                template = None;
+            }
+
+            let mut removed_txs = false;
+            // 10s expiration margin
+            current_block.transactions.retain(|tx| {
+                let expired =
+                    tx.timestamp + EXPIRATION_TIME + 10 < chrono::Utc::now().timestamp() as u64;
+                if expired {
+                    removed_txs = true;
+                }
+                !expired
+            });
+
+            if removed_txs {
+                current_block.meta.merkle_tree_root = MerkleTree::build(
+                    &current_block
+                        .transactions
+                        .iter()
+                        .map(|tx| tx.transaction_id.unwrap())
+                        .collect::<Vec<TransactionId>>(),
+                )
+                .root_hash();
+                current_block.meta.address_inclusion_filter =
+                    AddressInclusionFilter::create_filter(&current_block.transactions)?;
+                template = None;
+            }
+
+            let template = match &mut template {
+                Some(template) => template,
+                None => template.insert(HashingTemplate::new(&current_block)?),
+            };
+            let block_target = BigUint::from_bytes_be(&current_block.meta.block_pow_difficulty);
+            let batch_start = Instant::now();
+            // Only tracked for --verbose, the comparison isn't free
+            let track_best = tracing::enabled!(Level::DEBUG);
+            let mut best_hash: Option<BigUint> = None;
+            let mut batch_hashes = 0u64;
+            let mut batch_errors = 0u64;
+            for _ in 0..settings.batch_size {
+                if settings.refresh_timestamp {
+                    current_block.timestamp = chrono::Utc::now().timestamp() as u64;
+                }
+                current_block.nonce = nonces.next_nonce();
+                // A failed hash skips its nonce, the stats task warns about them and a batch of nothing but failures is an error
+                match pow.hash(template.hashing_buf(current_block.timestamp, current_block.nonce)?)
+                {
+                    Ok(hash) => {
+                        current_block.meta.hash = Some(hash);
+                        template_hashes += 1;
+                    }
+                    Err(e) => {
+                        batch_errors += 1;
+                        metrics.hash_errors.fetch_add(1, Ordering::Relaxed);
+                        *lock(&metrics.last_hash_error) = Some(e.to_string());
+                        continue;
+                    }
+                }
+
+                let hash = BigUint::from_bytes_be(&*current_block.meta.hash.unwrap());
+                let found_block = block_target > hash;
+                if found_block || **share_target.load() > hash {
+                    info!(
+                        "Found {} {}",
+                        if found_block { "block" } else { "share" },
+                        hash_format::show(&current_block.meta.hash.unwrap())
+                    );
+                    match validate_locally(&current_block, &*pow) {
+                        Ok(()) => submission_tx.blocking_send(FoundBlock {
+                            block: current_block.clone(),
+                            thread_id,
+                            time_to_find: template_start.elapsed(),
+                            hashes_to_find: template_hashes,
+                        })?,
+                        Err(e) => {
+                            error!("Found block failed local validation, not submitting: {e}")
+                        }
+                    }
+                }
+                hash_counter.fetch_add(1, Ordering::Relaxed);
+                batch_hashes += 1;
+                if track_best && best_hash.as_ref().is_none_or(|best| hash < *best) {
+                    best_hash = Some(hash);
+                }
+                // Once accepted the template is stale, end the batch early to pick up the epoch bump sooner
+                if found_block {
+                    break;
+                }
+            }
+            // Logged once per run of failed batches, they fail fast enough to flood the log otherwise
+            if batch_errors > 0 && batch_hashes == 0 && !hashing_failed {
+                hashing_failed = true;
+                error!(
+                    "Every one of {batch_errors} hashes in the batch failed ({}), check the [argon2] parameters and free memory",
+                    lock(&metrics.last_hash_error)
+                        .as_deref()
+                        .unwrap_or("unknown error")
+                );
+            } else if batch_hashes > 0 && hashing_failed {
+                hashing_failed = false;
+                info!("Hashing recovered");
+            }
+            if let Some(best_hash) = best_hash {
+                debug!(
+                    "Batch of {batch_hashes} hashes, best {:.3e} against target {:.3e}",
+                    biguint_to_f64(&best_hash),
+                    biguint_to_f64(&block_target)
+                );
+            }
+            if settings.max_cpu_percent < 100 {
+                // Busy for p% of the time means sleeping (100 - p) / p of the time spent hashing
+                thread::sleep(
+                    batch_start.elapsed() * (100 - settings.max_cpu_percent)
+                        / settings.max_cpu_percent,
+                );
+            }
+
+            Ok::<(), anyhow::Error>(())
+        })() {
+            error!("Mining error: {e}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{sync::Notify, time::timeout};
+
+    use super::*;
+    use crate::{
+        mock::{self, Miner, MockSource},
+        notify::BlockNotifier,
+        work,
+    };
+
+    /// One in sixteen hashes beats it, so finding a block takes a search but not a long one
+    const EASY_TARGET: [u8; 32] = {
+        let mut target = [0xffu8; 32];
+        target[0] = 0x0f;
+        target
+    };
+
+    fn source() -> MockSource {
+        MockSource::new(mock::template(
+            EASY_TARGET,
+            snap_coin::crypto::Hash::new_from_buf([1u8; 32]),
+        ))
+    }
+
+    /// Mine on the mock's template until the first block comes through the submission channel
+    async fn first_block(seed: Option<u64>) -> Block {
+        let source = source();
+        let (miner, mut submission_rx) = Miner::spawn(seed);
+        work::refresh(&source, &miner.job_tx, &miner.epoch, &miner.metrics)
+            .await
+            .unwrap();
+        let found = timeout(Duration::from_secs(30), submission_rx.recv())
+            .await
+            .expect("no block found within 30s")
+            .unwrap();
+        // Closed first, so a thread still finding blocks can't block on a full channel while being joined
+        drop(submission_rx);
+        miner.stop();
+        found.block
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn submitted_block_beats_its_target() {
+        let source = Arc::new(source());
+        let (miner, submission_rx) = Miner::spawn(None);
+        work::refresh(&*source, &miner.job_tx, &miner.epoch, &miner.metrics)
+            .await
+            .unwrap();
+
+        // Through the miner's own submission path, which asks for a refresh once a block is accepted
+        let refresh_request = Arc::new(Notify::new());
+        let submitter = tokio::spawn({
+            let source = source.clone();
+            let epoch = miner.epoch.clone();
+            let metrics = miner.metrics.clone();
+            let refresh_request = refresh_request.clone();
+            async move {
+                let notifier = BlockNotifier {
+                    webhook: None,
+                    block_log_dir: None,
+                };
+                work::submit_found(
+                    &*source,
+                    submission_rx,
+                    false,
+                    &epoch,
+                    &refresh_request,
+                    &metrics,
+                    &notifier,
+                )
+                .await
+            }
+        });
+        timeout(Duration::from_secs(30), refresh_request.notified())
+            .await
+            .expect("no block submitted within 30s");
+        miner.stop();
+        // Ends once the stopped thread has dropped its sender
+        submitter.await.unwrap();
+
+        let submitted = lock(&source.submitted);
+        assert!(!submitted.is_empty());
+        let pow = mock::test_pow();
+        for block in submitted.iter() {
+            let hash = block.meta.hash.expect("submitted without a hash");
+            assert!(BigUint::from_bytes_be(&*hash) < BigUint::from_bytes_be(&EASY_TARGET));
+            assert_eq!(hash, pow.hash(&block.get_hashing_buf().unwrap()).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn seeded_mining_finds_the_same_block() {
+        let first = first_block(Some(7)).await;
+        let again = first_block(Some(7)).await;
+        assert_eq!(first.nonce, again.nonce);
+        assert_eq!(first.meta.hash, again.meta.hash);
+        // The seed, not the template, picks where the walk starts
+        assert_ne!(first_block(Some(8)).await.nonce, first.nonce);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+};
+
+use arc_swap::ArcSwap;
+use num_bigint::BigUint;
+use snap_coin::{
+    core::block::Block,
+    crypto::{Hash, address_inclusion_filter::AddressInclusionFilter},
+};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    hashing::{Argon2Params, ProofOfWork},
+    lock::lock,
+    metrics::Metrics,
+    miner::{self, FoundBlock, MinerSettings, MinerShared},
+    work::{Submitted, WorkSource},
+};
+
+/// Argon2 at its smallest, hashes in well under a millisecond so tests can afford to search for a block
+/// Only the mock accepts blocks hashed this way
+pub fn test_pow() -> Arc<dyn ProofOfWork> {
+    Arc::new(
+        Argon2Params {
+            memory_cost: 8,
+            time_cost: 1,
+            parallelism: 1,
+        }
+        .pow()
+        .unwrap(),
+    )
+}
+
+/// A template with no transactions on `previous_block` whose hash has to beat `target`, stamped at a fixed time so its hashing buffer is the same every run
+pub fn template(target: [u8; 32], previous_block: Hash) -> Block {
+    let mut block = Block::new_block_now(
+        vec![],
+        &target,
+        &[0xffu8; 32],
+        previous_block,
+        // The merkle root of no transactions
+        &[0u8; 32],
+        AddressInclusionFilter::create_filter(&[]).unwrap(),
+    );
+    block.timestamp = 1_700_000_000;
+    block
+}
+
+/// A node that hands out a canned template and takes every block submitted to it
+/// An accepted block becomes the new tip, so the next template builds on it like a real node's would
+/// Like `NodeSource`, a template is handed out once until the tip moves or `forget_template` is called
+pub struct MockSource {
+    template: Mutex<Block>,
+    /// The tip the last template handed out was built on
+    handed_out: Mutex<Option<Hash>>,
+    /// Every block submitted, in order
+    pub submitted: Mutex<Vec<Block>>,
+}
+
+impl MockSource {
+    pub fn new(template: Block) -> Self {
+        MockSource {
+            template: Mutex::new(template),
+            handed_out: Mutex::new(None),
+            submitted: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl WorkSource for MockSource {
+    async fn current_work(&self) -> Result<Option<Block>, anyhow::Error> {
+        let template = lock(&self.template).clone();
+        let tip = Some(template.meta.previous_block);
+        if std::mem::replace(&mut *lock(&self.handed_out), tip) == tip {
+            return Ok(None);
+        }
+        Ok(Some(template))
+    }
+
+    fn difficulty(&self) -> BigUint {
+        BigUint::from_bytes_be(&lock(&self.template).meta.block_pow_difficulty)
+    }
+
+    fn height(&self) -> Option<usize> {
+        Some(lock(&self.submitted).len())
+    }
+
+    fn forget_template(&self) {
+        *lock(&self.handed_out) = None;
+    }
+
+    async fn submit(&self, block: &Block) -> Result<Submitted, anyhow::Error> {
+        lock(&self.submitted).push(block.clone());
+        let mut tip = lock(&self.template);
+        *tip = template(tip.meta.block_pow_difficulty, block.meta.hash.unwrap());
+        Ok(Submitted::Block)
+    }
+}
+
+/// One mining thread on templates sent through `job_tx`, with the flags the rest of the miner would hold
+pub struct Miner {
+    pub job_tx: broadcast::Sender<Block>,
+    pub epoch: Arc<AtomicU64>,
+    pub metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Miner {
+    /// Start a single mining thread, it waits for the first template on `job_tx` and sends what it finds to the returned receiver
+    pub fn spawn(seed: Option<u64>) -> (Self, mpsc::Receiver<FoundBlock>) {
+        let (job_tx, _) = broadcast::channel(1);
+        let (submission_tx, submission_rx) = mpsc::channel(16);
+        let shared = MinerShared {
+            submission_tx,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            epoch: Arc::new(AtomicU64::new(0)),
+            share_target: Arc::new(ArcSwap::from_pointee(BigUint::ZERO)),
+            settings: MinerSettings {
+                batch_size: 16,
+                max_cpu_percent: 100,
+                pin_threads: false,
+                refresh_timestamp: false,
+                seed,
+            },
+            pow: test_pow(),
+            metrics: Arc::new(Metrics::default()),
+            paused: Arc::new(AtomicBool::new(false)),
+        };
+        let threads =
+            miner::spawn_mining_threads(1, &job_tx, &[Arc::new(AtomicU64::new(0))], &shared);
+        let miner = Miner {
+            job_tx,
+            epoch: shared.epoch,
+            metrics: shared.metrics,
+            shutdown: shared.shutdown,
+            threads,
+        };
+        (miner, submission_rx)
+    }
+
+    /// Stop the thread and wait for it, closing the job channel wakes it if it is waiting for work
+    /// A blocking join, the runtime's other workers keep a `submit_found` draining the channel going meanwhile
+    pub fn stop(self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        drop(self.job_tx);
+        for thread in self.threads {
+            thread.join().unwrap();
+        }
+    }
+}
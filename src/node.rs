@@ -0,0 +1,702 @@
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use num_bigint::BigUint;
+use snap_coin::{
+    api::client::Client,
+    blockchain_data_provider::{BlockchainDataProvider, BlockchainDataProviderError},
+    build_block,
+    core::{
+        block::{Block, BlockError},
+        blockchain::BlockchainError,
+        transaction::{Transaction, TransactionId},
+    },
+    crypto::Hash,
+    economics::EXPIRATION_TIME,
+    to_snap,
+};
+use tokio::{
+    net::{TcpStream, lookup_host},
+    sync::{Notify, mpsc},
+    time::{sleep, timeout},
+};
+use tracing::{Instrument, info, warn};
+
+use crate::{
+    SUBMIT_RETRY_DELAY, hash_format,
+    lock::lock,
+    metrics::Metrics,
+    payout::Payouts,
+    style,
+    tls::NodeTls,
+    tx_selector::{AllTxs, TxSelector},
+    work::{Submitted, WorkSource, miner_reward},
+};
+
+/// A node, or with `connect_tcp` a pool, as `host:port`, where the host is an IP or a name that is resolved again on every connect
+/// Always holds a port, `parse` checks for it
+#[derive(Clone, PartialEq)]
+pub struct NodeAddress(String);
+
+impl NodeAddress {
+    pub fn parse(address: &str) -> Result<Self, anyhow::Error> {
+        let invalid = |reason: String| {
+            anyhow!(
+                "Invalid node address \"{}\", expected <host>:<port>: {}",
+                address,
+                reason
+            )
+        };
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("missing port".to_string()))?;
+        if host.is_empty() {
+            return Err(invalid("missing host".to_string()));
+        }
+        port.parse::<u16>().map_err(|e| invalid(e.to_string()))?;
+        Ok(NodeAddress(address.to_string()))
+    }
+
+    /// The host without its port, and without brackets around an IPv6 address
+    pub fn host(&self) -> &str {
+        let (host, _) = self.0.rsplit_once(':').unwrap();
+        host.strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host)
+    }
+
+    /// Every address the host resolves to, in the resolver's order
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, std::io::Error> {
+        Ok(lookup_host(self.0.as_str()).await?.collect())
+    }
+
+    /// Resolve the host and connect to the first of its addresses that answers, through a tunnel with TLS
+    pub async fn connect(&self, tls: Option<&NodeTls>) -> Result<Client, std::io::Error> {
+        let mut last_error = std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "the host resolved to no addresses",
+        );
+        for address in self.resolve().await? {
+            let client = async {
+                match tls {
+                    Some(tls) => Client::connect(tls.tunnel(self, address).await?).await,
+                    None => Client::connect(address).await,
+                }
+            };
+            match client.await {
+                Ok(client) => return Ok(client),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Resolve the host and open a plain TCP connection to the first of its addresses that answers
+    pub async fn connect_tcp(&self) -> Result<TcpStream, std::io::Error> {
+        let mut last_error = std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "the host resolved to no addresses",
+        );
+        for address in self.resolve().await? {
+            match TcpStream::connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl fmt::Display for NodeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A node request still unanswered after `[node] request_timeout_secs`
+/// Its answer can still arrive on the connection and be read as the next request's, so no request goes out on that connection again
+#[derive(Debug)]
+struct TimedOut(&'static str);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Node request {} timed out", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// How often a request waiting on a reconnect checks whether the job task has replaced the connection
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A connection to the node, swapped for a new one by the job task whenever it reconnects
+/// snap-coin's `Client` locks its stream separately to write a request and to read the answer, so two requests at once can read each other's answers, requests on one connection take turns instead
+pub struct Connection {
+    client: Arc<Client>,
+    turn: Arc<tokio::sync::Mutex<()>>,
+    /// Set as soon as a request on it times out, requests then wait for the job task to replace it
+    timed_out: AtomicBool,
+}
+
+impl Connection {
+    pub fn new(client: Client) -> Self {
+        Connection {
+            client: Arc::new(client),
+            turn: Arc::new(tokio::sync::Mutex::new(())),
+            timed_out: AtomicBool::new(false),
+        }
+    }
+}
+
+/// `[node] broadcast_submit`, found blocks go to every configured node at once instead of just the active one
+pub struct Broadcast {
+    pub nodes: Arc<ArcSwap<Vec<NodeAddress>>>,
+    pub connect_timeout: Duration,
+    pub tls: Option<NodeTls>,
+}
+
+/// How long an unchanged template is kept, its reward transaction expires `EXPIRATION_TIME` after it was built
+const TEMPLATE_MAX_AGE: Duration = Duration::from_secs(EXPIRATION_TIME / 2);
+
+/// What the latest template was built from, a refresh finding the same skips rebuilding it
+struct TemplateKey {
+    tip: Option<Hash>,
+    transactions: Vec<Option<TransactionId>>,
+    built: Instant,
+}
+
+/// What submitting a block to a single node came to
+type NodeVerdict = Result<Submitted, anyhow::Error>;
+
+/// What the node's answer to a submitted block means
+/// The node answers Ok to the block it saw last without checking it again, so an Ok block is looked up with `height_by_hash` to tell it from a resubmission
+async fn verdict(
+    status: Result<(), BlockchainError>,
+    height_by_hash: impl Future<Output = Result<Option<usize>, anyhow::Error>>,
+) -> Submitted {
+    match status {
+        Ok(()) => match height_by_hash.await {
+            Ok(Some(_)) => Submitted::Block,
+            Ok(None) => Submitted::Duplicate,
+            // The node did just accept something, most likely this block
+            Err(e) => {
+                warn!("Could not look the accepted block up in the chain, counting it anyway: {e}");
+                Submitted::Block
+            }
+        },
+        // Difficulty moves with every block, so a template from before the tip moved fails on it too
+        Err(
+            reason @ (BlockchainError::InvalidPreviousBlockHash
+            | BlockchainError::InvalidTimestamp
+            | BlockchainError::Block(BlockError::DifficultyMismatch)),
+        ) => Submitted::Stale(reason.to_string()),
+        Err(reason) => Submitted::Invalid(reason.to_string()),
+    }
+}
+
+/// Log what `node` made of a broadcast block, `order` tells the first acceptance from the later ones
+fn log_verdict(node: &NodeAddress, verdict: &NodeVerdict, order: &str) {
+    match verdict {
+        Ok(Submitted::Block) => info!("Node {node} accepted the block {order}"),
+        Ok(Submitted::Duplicate) => info!("Node {node} had seen the block already"),
+        Ok(Submitted::Stale(reason)) => warn!("Node {node} rejected the block as stale: {reason}"),
+        Ok(Submitted::Invalid(reason)) => {
+            warn!("Node {node} rejected the block as invalid: {reason}")
+        }
+        Ok(Submitted::Share) => {}
+        Err(e) => warn!("Failed to submit the block to node {node}: {e}"),
+    }
+}
+
+/// Solo mining against a node's API, templates are built locally and pay out to the next of `payouts`
+pub struct NodeSource {
+    /// Swapped out by the job task whenever the connection to a node is re-established
+    pub connection: ArcSwap<Connection>,
+    /// The node `connection` is to, swapped together with it
+    pub node: ArcSwap<NodeAddress>,
+    broadcast: Option<Broadcast>,
+    /// `[node] min_submit_interval_ms`, zero submits right away
+    min_submit_interval: Duration,
+    last_submit: Mutex<Option<Instant>>,
+    payouts: Payouts,
+    submit_retries: u32,
+    /// Which mempool transactions go into templates, `AllTxs` unless `with_selector` sets another
+    selector: Box<dyn TxSelector>,
+    /// Block pow target of the latest template
+    difficulty: ArcSwap<BigUint>,
+    /// Height the latest template would be mined at
+    height: AtomicUsize,
+    /// Set while templates go without transactions because the mempool can't be fetched
+    mempool_failing: AtomicBool,
+    last_template: Mutex<Option<TemplateKey>>,
+    request_timeout: Duration,
+    /// Asks the job task to reconnect, notified when a request times out
+    reconnect: Arc<Notify>,
+    /// Where request times go, and `[node] slow_request_ms`, zero never warns
+    metrics: Option<Arc<Metrics>>,
+    slow_request: Duration,
+}
+
+impl NodeSource {
+    pub fn new(
+        client: Client,
+        node: NodeAddress,
+        payouts: Payouts,
+        submit_retries: u32,
+        request_timeout: Duration,
+        reconnect: Arc<Notify>,
+    ) -> Self {
+        NodeSource {
+            connection: ArcSwap::from_pointee(Connection::new(client)),
+            node: ArcSwap::from_pointee(node),
+            broadcast: None,
+            min_submit_interval: Duration::ZERO,
+            last_submit: Mutex::new(None),
+            payouts,
+            submit_retries,
+            selector: Box::new(AllTxs),
+            difficulty: ArcSwap::from_pointee(BigUint::ZERO),
+            height: AtomicUsize::new(0),
+            mempool_failing: AtomicBool::new(false),
+            last_template: Mutex::new(None),
+            request_timeout,
+            reconnect,
+            metrics: None,
+            slow_request: Duration::ZERO,
+        }
+    }
+
+    /// Pick template transactions with `selector` instead of taking the whole mempool
+    pub fn with_selector(mut self, selector: Box<dyn TxSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    pub fn broadcast_to(mut self, broadcast: Broadcast) -> Self {
+        self.broadcast = Some(broadcast);
+        self
+    }
+
+    /// Time every request into `metrics`, warning about those slower than `slow_request`
+    pub fn record_latency(mut self, metrics: Arc<Metrics>, slow_request: Duration) -> Self {
+        self.metrics = Some(metrics);
+        self.slow_request = slow_request;
+        self
+    }
+
+    /// Space submissions at least `interval` apart, a block found sooner waits for its turn
+    pub fn throttle_submits(mut self, interval: Duration) -> Self {
+        self.min_submit_interval = interval;
+        self
+    }
+
+    /// Make the request `call` sends on the client once the requests before it on the connection are answered
+    /// One still unanswered after the request timeout fails and has the connection re-established, later requests wait for the new one
+    async fn request<T, E: Into<anyhow::Error>, F: Future<Output = Result<T, E>>>(
+        &self,
+        name: &'static str,
+        call: impl FnOnce(Arc<Client>) -> F,
+    ) -> Result<T, anyhow::Error> {
+        let (connection, _turn) = loop {
+            let connection = self.usable_connection().await?;
+            let turn = connection.turn.clone().lock_owned().await;
+            // A request ahead of this one timed out while it waited for its turn
+            if !connection.timed_out.load(Ordering::Relaxed) {
+                break (connection, turn);
+            }
+        };
+        let start = Instant::now();
+        match timeout(self.request_timeout, call(connection.client.clone())).await {
+            Ok(result) => {
+                let elapsed = start.elapsed();
+                if let Some(metrics) = &self.metrics {
+                    metrics.node_latency.record(name, elapsed);
+                }
+                // Slow template requests leave the threads on a stale tip meanwhile
+                if !self.slow_request.is_zero() && elapsed > self.slow_request {
+                    warn!(
+                        "Node request {name} took {}ms (node.slow_request_ms is {})",
+                        elapsed.as_millis(),
+                        self.slow_request.as_millis()
+                    );
+                }
+                result.map_err(Into::into)
+            }
+            Err(_) => {
+                warn!(
+                    "Node request {name} timed out after {}s, reconnecting",
+                    self.request_timeout.as_secs()
+                );
+                connection.timed_out.store(true, Ordering::Relaxed);
+                self.reconnect.notify_one();
+                Err(TimedOut(name).into())
+            }
+        }
+    }
+
+    /// Submit to the active node, network errors are retried up to `submit_retries` times
+    async fn submit_with_retries(&self, block: &Block) -> Result<Submitted, anyhow::Error> {
+        // Only network errors are retried, a rejection means the block is stale or invalid
+        let mut retries = 0;
+        let status = loop {
+            match self
+                .request("submit_block", |client| async move {
+                    client.submit_block(block.clone()).await
+                })
+                .await
+            {
+                Ok(status) => break status,
+                Err(e)
+                    if retries < self.submit_retries
+                        && **self.difficulty.load()
+                            > BigUint::from_bytes_be(&*block.meta.hash.unwrap()) =>
+                {
+                    retries += 1;
+                    warn!(
+                        "Submit failed: {e}, retrying ({retries}/{})",
+                        self.submit_retries
+                    );
+                    // After a timeout the retry waits for the reconnect, the old connection can still deliver the answer
+                    sleep(SUBMIT_RETRY_DELAY * retries).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if retries > 0 {
+            info!(
+                "Submission {} after {retries} retries",
+                if status.is_ok() {
+                    "accepted"
+                } else {
+                    "rejected"
+                }
+            );
+        }
+        Ok(verdict(
+            status,
+            self.request("get_height_by_hash", |client| async move {
+                client.get_height_by_hash(block.meta.hash.unwrap()).await
+            }),
+        )
+        .await)
+    }
+
+    /// The current connection, once the job task has replaced one a request timed out on
+    /// Fails if that takes longer than the request timeout
+    async fn usable_connection(&self) -> Result<Arc<Connection>, anyhow::Error> {
+        timeout(self.request_timeout, async {
+            loop {
+                let connection = self.connection.load_full();
+                if !connection.timed_out.load(Ordering::Relaxed) {
+                    return connection;
+                }
+                sleep(RECONNECT_POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The connection to the node wasn't re-established within {}s of a timed out request",
+                self.request_timeout.as_secs()
+            )
+        })
+    }
+
+    /// Submit to every configured node concurrently, the block counts as accepted once any of them accepts it
+    /// The active node is sent it over the open connection in turn with its other requests, every other node gets a connection just for this block, and none is retried
+    async fn submit_to_all(
+        &self,
+        broadcast: &Broadcast,
+        block: &Block,
+    ) -> Result<Submitted, anyhow::Error> {
+        let active = self.node.load_full();
+        let (verdict_tx, mut verdict_rx) = mpsc::unbounded_channel::<(NodeAddress, NodeVerdict)>();
+        for node in broadcast.nodes.load().iter() {
+            let connection = (*node == *active).then(|| self.connection.load_full());
+            let node = node.clone();
+            let block = block.clone();
+            let tls = broadcast.tls.clone();
+            let connect_timeout = broadcast.connect_timeout;
+            let request_timeout = self.request_timeout;
+            let reconnect = self.reconnect.clone();
+            let verdict_tx = verdict_tx.clone();
+            tokio::spawn(
+                async move {
+                    let verdict = async {
+                        let (client, _turn) = match &connection {
+                            Some(connection) => (
+                                connection.client.clone(),
+                                Some(connection.turn.lock().await),
+                            ),
+                            None => (
+                                Arc::new(
+                                    timeout(connect_timeout, node.connect(tls.as_ref()))
+                                        .await
+                                        .map_err(|_| {
+                                            anyhow!(
+                                                "connecting timed out after {}s",
+                                                connect_timeout.as_secs()
+                                            )
+                                        })??,
+                                ),
+                                None,
+                            ),
+                        };
+                        if connection
+                            .as_ref()
+                            .is_some_and(|connection| connection.timed_out.load(Ordering::Relaxed))
+                        {
+                            return Err(anyhow!("an earlier request timed out, reconnecting"));
+                        }
+                        let timed_out = |_| {
+                            if let Some(connection) = &connection {
+                                connection.timed_out.store(true, Ordering::Relaxed);
+                                reconnect.notify_one();
+                            }
+                            anyhow!("timed out after {}s", request_timeout.as_secs())
+                        };
+                        let hash = block.meta.hash.unwrap();
+                        let status = timeout(request_timeout, client.submit_block(block))
+                            .await
+                            .map_err(timed_out)??;
+                        Ok(verdict(status, async {
+                            Ok(timeout(request_timeout, client.get_height_by_hash(hash))
+                                .await
+                                .map_err(timed_out)??)
+                        })
+                        .await)
+                    };
+                    let verdict = verdict.await;
+                    let _ = verdict_tx.send((node, verdict));
+                }
+                .in_current_span(),
+            );
+        }
+        drop(verdict_tx);
+
+        let mut outcome = None;
+        let mut last_error = None;
+        while let Some((node, verdict)) = verdict_rx.recv().await {
+            log_verdict(&node, &verdict, "first");
+            match verdict {
+                Ok(Submitted::Block) => {
+                    // The slower nodes are still reported, without holding up the next template
+                    tokio::spawn(
+                        async move {
+                            while let Some((node, verdict)) = verdict_rx.recv().await {
+                                log_verdict(&node, &verdict, "too");
+                            }
+                        }
+                        .in_current_span(),
+                    );
+                    return Ok(Submitted::Block);
+                }
+                Ok(submitted) => {
+                    outcome.get_or_insert(submitted);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match (outcome, last_error) {
+            (Some(submitted), _) => Ok(submitted),
+            (None, Some(e)) => Err(anyhow!("No node took the block, the last error: {e}")),
+            (None, None) => Err(anyhow!("No node to submit the block to")),
+        }
+    }
+
+    /// Peers the node is connected to, the API reports nothing else about its sync state
+    pub async fn peer_count(&self) -> Result<usize, anyhow::Error> {
+        Ok(self
+            .request(
+                "get_peers",
+                |client| async move { client.get_peers().await },
+            )
+            .await?
+            .len())
+    }
+
+    /// The cheapest request there is, to see if the node answers at all
+    pub async fn probe(&self) -> Result<(), anyhow::Error> {
+        self.request(
+            "get_height",
+            |client| async move { client.get_height().await },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Log the balance of every payout address each `interval` until the task is dropped
+    /// Gives up with a warning right away if the node can't answer a balance query
+    pub async fn show_balances(&self, interval: Duration) {
+        let mut first = true;
+        loop {
+            for address in self.payouts.addresses() {
+                let address = *address;
+                match self
+                    .request("get_balance", |client| async move {
+                        client.get_balance(address).await
+                    })
+                    .await
+                {
+                    Ok(balance) => info!(
+                        "Balance of {}: {} SNAP",
+                        address.dump_base36(),
+                        to_snap(balance)
+                    ),
+                    Err(e) if first => {
+                        warn!(
+                            "Balance lookup failed ({e}), the node may not support it, not showing balance"
+                        );
+                        return;
+                    }
+                    Err(e) => warn!("Failed to fetch balance of {}: {e}", address.dump_base36()),
+                }
+            }
+            first = false;
+            sleep(interval).await;
+        }
+    }
+}
+
+/// The mempool transactions a template takes, as `selector` picks them from the ones not about to expire
+async fn get_current_mempool(
+    client: &Client,
+    selector: &dyn TxSelector,
+) -> Result<Vec<Transaction>, BlockchainDataProviderError> {
+    let mut mempool = client.get_mempool().await?;
+    // Add a 5s anti expiration buffer
+    mempool.retain(|tx| tx.timestamp + 5 < EXPIRATION_TIME + chrono::Utc::now().timestamp() as u64);
+    Ok(selector.select(mempool))
+}
+
+impl WorkSource for NodeSource {
+    async fn current_work(&self) -> Result<Option<Block>, anyhow::Error> {
+        let height = self
+            .request(
+                "get_height",
+                |client| async move { client.get_height().await },
+            )
+            .await?;
+        // A reorg can replace the tip without changing the height
+        let tip = match height.checked_sub(1) {
+            Some(tip_height) => {
+                self.request("get_block_hash_by_height", |client| async move {
+                    client.get_block_hash_by_height(tip_height).await
+                })
+                .await?
+            }
+            None => None,
+        };
+        let mempool = if !self.selector.wants_mempool() {
+            vec![]
+        } else {
+            match self
+                .request("get_mempool", |client| async move {
+                    get_current_mempool(&client, &*self.selector).await
+                })
+                .await
+            {
+                Ok(mempool) => {
+                    if self.mempool_failing.swap(false, Ordering::Relaxed) {
+                        info!("Mempool fetch recovered, templates include transactions again");
+                    }
+                    mempool
+                }
+                // An empty block on the current tip still tracks the chain, unlike keeping the old template
+                Err(e) => {
+                    if !self.mempool_failing.swap(true, Ordering::Relaxed) {
+                        warn!("Mempool fetch failed ({e}), mining empty blocks until it recovers");
+                    }
+                    vec![]
+                }
+            }
+        };
+        let transactions: Vec<Option<TransactionId>> =
+            mempool.iter().map(|tx| tx.transaction_id).collect();
+        if lock(&self.last_template).as_ref().is_some_and(|last| {
+            last.tip == tip
+                && last.transactions == transactions
+                && last.built.elapsed() < TEMPLATE_MAX_AGE
+        }) {
+            return Ok(None);
+        }
+        // Only a template actually built takes the next payout address
+        let payout = self.payouts.next();
+        let block = self
+            .request("build_block", |client| async move {
+                build_block(&*client, &mempool, payout).await
+            })
+            .await?;
+        *lock(&self.last_template) = Some(TemplateKey {
+            tip,
+            transactions,
+            built: Instant::now(),
+        });
+        if self.payouts.addresses().len() > 1 {
+            info!("Template pays to {}", payout.dump_base36());
+        }
+        self.difficulty.store(Arc::new(BigUint::from_bytes_be(
+            &block.meta.block_pow_difficulty,
+        )));
+        self.height.store(height, Ordering::Relaxed);
+        Ok(Some(block))
+    }
+
+    fn difficulty(&self) -> BigUint {
+        (**self.difficulty.load()).clone()
+    }
+
+    fn height(&self) -> Option<usize> {
+        Some(self.height.load(Ordering::Relaxed))
+    }
+
+    fn forget_template(&self) {
+        *lock(&self.last_template) = None;
+    }
+
+    async fn submit(&self, block: &Block) -> Result<Submitted, anyhow::Error> {
+        if !self.min_submit_interval.is_zero() {
+            let wait = lock(&self.last_submit).map(|last| {
+                (last + self.min_submit_interval).saturating_duration_since(Instant::now())
+            });
+            if let Some(wait) = wait.filter(|wait| !wait.is_zero()) {
+                info!(
+                    "Throttling submission of block {} for {}ms (node.min_submit_interval_ms)",
+                    hash_format::show(&block.meta.hash.unwrap()),
+                    wait.as_millis()
+                );
+                sleep(wait).await;
+            }
+            *lock(&self.last_submit) = Some(Instant::now());
+        }
+        let submitted = match &self.broadcast {
+            Some(broadcast) => self.submit_to_all(broadcast, block).await,
+            None => self.submit_with_retries(block).await,
+        };
+        // Whatever came of it, the next template is built afresh, a rejection can mean the node sees a tip this miner doesn't
+        self.forget_template();
+        let submitted = submitted?;
+        if !matches!(submitted, Submitted::Block) {
+            return Ok(submitted);
+        }
+
+        info!(
+            "{}",
+            style::green(format!(
+                "Block validated! Miner rewarded {} SNAP",
+                to_snap(miner_reward(block))
+            ))
+        );
+        Ok(Submitted::Block)
+    }
+}
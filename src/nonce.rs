@@ -0,0 +1,121 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use snap_coin::core::block::Block;
+
+/// A disjoint slice of the nonce space owned by one mining thread
+/// Walked sequentially, so no two threads ever try the same nonce on the same template
+pub struct NonceSlice {
+    start: u64,
+    len: u64,
+    next: u64,
+    /// Picks the walk's offsets instead of the block when mining with `--seed`
+    rng: Option<StdRng>,
+}
+
+impl NonceSlice {
+    pub fn new(thread_id: u64, thread_count: u64, seed: Option<u64>) -> Self {
+        let len = u64::MAX / thread_count.max(1);
+        NonceSlice {
+            start: thread_id * len,
+            len,
+            next: 0,
+            rng: seed.map(|seed| StdRng::seed_from_u64(seed ^ thread_id)),
+        }
+    }
+
+    /// Restart the walk at an offset derived from the block, so every template explores a different region of the slice
+    /// A seeded slice draws the offset from its rng, so the same templates are walked the same way every run
+    pub fn reset(&mut self, block: &Block) {
+        self.next = match &mut self.rng {
+            Some(rng) => rng.random_range(0..self.len),
+            None => {
+                let mut seed = [0u8; 8];
+                seed.copy_from_slice(&block.meta.merkle_tree_root[..8]);
+                u64::from_be_bytes(seed) % self.len
+            }
+        };
+    }
+
+    /// Next nonce to try, wrapping around inside the slice once it is exhausted
+    pub fn next_nonce(&mut self) -> u64 {
+        let nonce = self.start + self.next;
+        self.next = (self.next + 1) % self.len;
+        nonce
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use snap_coin::crypto::Hash;
+
+    use super::*;
+    use crate::mock;
+
+    /// 3 divides u64::MAX, the others leave a remainder at the top of the range that no slice owns
+    const THREAD_COUNTS: [u64; 6] = [1, 2, 3, 7, 10, 1_000];
+
+    fn block() -> Block {
+        let mut block = mock::template([0xffu8; 32], Hash::new_from_buf([1u8; 32]));
+        block.meta.merkle_tree_root = [0xabu8; 32];
+        block
+    }
+
+    #[test]
+    fn adjacent_slices_do_not_overlap() {
+        for thread_count in THREAD_COUNTS {
+            let slices: Vec<NonceSlice> = (0..thread_count)
+                .map(|thread_id| NonceSlice::new(thread_id, thread_count, None))
+                .collect();
+            for pair in slices.windows(2) {
+                assert!(pair[0].start + pair[0].len <= pair[1].start);
+            }
+            let last = slices.last().unwrap();
+            assert!(last.start.checked_add(last.len - 1).is_some());
+        }
+    }
+
+    /// The first 1000 nonces a thread tries on `block`, starting `skip` nonces before the end of its slice if given
+    fn walk(thread_id: u64, thread_count: u64, seed: Option<u64>, skip: Option<u64>) -> Vec<u64> {
+        let mut nonces = NonceSlice::new(thread_id, thread_count, seed);
+        nonces.reset(&block());
+        if let Some(skip) = skip {
+            nonces.next = nonces.len - skip;
+        }
+        (0..1_000).map(|_| nonces.next_nonce()).collect()
+    }
+
+    #[test]
+    fn adjacent_threads_never_try_the_same_nonce() {
+        for thread_count in THREAD_COUNTS.into_iter().filter(|count| *count > 1) {
+            for seed in [None, Some(1)] {
+                // Half way through the walk both threads wrap back to the start of their slices
+                for skip in [None, Some(500)] {
+                    for thread_id in [0, thread_count - 2] {
+                        let first: HashSet<u64> = walk(thread_id, thread_count, seed, skip)
+                            .into_iter()
+                            .collect();
+                        let second = walk(thread_id + 1, thread_count, seed, skip);
+                        assert!(
+                            second.iter().all(|nonce| !first.contains(nonce)),
+                            "threads {thread_id} and {} of {thread_count}",
+                            thread_id + 1
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn walk_wraps_inside_its_slice() {
+        for thread_count in THREAD_COUNTS {
+            let thread_id = thread_count - 1;
+            let mut nonces = NonceSlice::new(thread_id, thread_count, None);
+            nonces.reset(&block());
+            nonces.next = nonces.len - 1;
+            assert_eq!(nonces.next_nonce(), nonces.start + (nonces.len - 1));
+            assert_eq!(nonces.next_nonce(), nonces.start);
+        }
+    }
+}
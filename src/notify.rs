@@ -0,0 +1,157 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{Instrument, warn};
+
+use crate::{hash_format, miner::FoundBlock};
+
+/// Longest a webhook request may take before it is given up on
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything that hears about a block once the node accepts it, all optional
+/// The webhook also receives the alerts of `[alert]` that have `webhook` set
+pub struct BlockNotifier {
+    pub webhook: Option<Webhook>,
+    /// Every accepted block is written here as `<hash>.json`
+    pub block_log_dir: Option<PathBuf>,
+}
+
+impl BlockNotifier {
+    /// Report an accepted block, failures are only logged so mining carries on regardless
+    pub fn block_accepted(&self, found: &FoundBlock, height: Option<usize>) {
+        let hash = hash_format::show(&found.block.meta.hash.unwrap());
+        if let Some(dir) = &self.block_log_dir
+            && let Err(e) = write_block_log(dir, &hash, found)
+        {
+            warn!("Failed to write block {hash} to {}: {e}", dir.display());
+        }
+        if let Some(webhook) = &self.webhook {
+            let webhook = webhook.clone();
+            let body = json!({
+                "hash": hash,
+                "height": height,
+                "thread": found.thread_id,
+                "time_to_find_secs": found.time_to_find.as_secs_f64(),
+            });
+            tokio::spawn(
+                async move {
+                    if let Err(e) = webhook.post(&body).await {
+                        warn!("Failed to notify webhook: {e}");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    /// POST an alert raised or cleared by the stats task to the webhook, if there is one
+    pub fn alert(&self, body: Value) {
+        if let Some(webhook) = &self.webhook {
+            let webhook = webhook.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = webhook.post(&body).await {
+                        warn!("Failed to send alert to webhook: {e}");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+}
+
+fn write_block_log(dir: &Path, hash: &str, found: &FoundBlock) -> Result<(), anyhow::Error> {
+    // Created on every write, so removing the directory mid-run doesn't stop the log
+    fs::create_dir_all(dir)?;
+    fs::write(
+        dir.join(format!("{hash}.json")),
+        serde_json::to_string_pretty(&found.block)?,
+    )?;
+    Ok(())
+}
+
+/// A plain HTTP endpoint found blocks are POSTed to as JSON
+#[derive(Clone)]
+pub struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Webhook {
+    /// Parse an `http://host[:port][/path]` url, TLS is not supported
+    pub fn parse(url: &str) -> Result<Self, anyhow::Error> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            anyhow!(
+                "Invalid notify.webhook_url \"{}\", only http:// urls are supported",
+                url
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>().map_err(|e| {
+                    anyhow!("Invalid port in notify.webhook_url \"{}\": {}", url, e)
+                })?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!(
+                "Invalid notify.webhook_url \"{}\", missing host",
+                url
+            ));
+        }
+        Ok(Webhook {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// POST `body` and fail unless the endpoint answers with a 2xx status
+    pub async fn post(&self, body: &Value) -> Result<(), anyhow::Error> {
+        timeout(WEBHOOK_TIMEOUT, self.send(body.to_string()))
+            .await
+            .map_err(|_| anyhow!("Webhook timed out after {}s", WEBHOOK_TIMEOUT.as_secs()))?
+    }
+
+    async fn send(&self, body: String) -> Result<(), anyhow::Error> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream
+            .write_all(
+                format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    self.path,
+                    self.host,
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        // Only the status line matters, so a single read is enough
+        let mut response = [0u8; 1024];
+        let read = stream.read(&mut response).await?;
+        let response = String::from_utf8_lossy(&response[..read]);
+        let status = response.lines().next().unwrap_or("");
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(anyhow!("Webhook answered \"{}\"", status)),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::anyhow;
+use rand::Rng;
+use snap_coin::crypto::keys::Public;
+
+/// Which wallet each new block template pays its reward to
+pub struct Payouts {
+    addresses: Vec<Public>,
+    /// Running totals of the configured weights, `None` rotates through `addresses` round-robin instead
+    cumulative_weights: Option<Vec<u64>>,
+    /// Index of the next round-robin recipient, wrapped on use
+    next: AtomicUsize,
+}
+
+impl Payouts {
+    pub fn round_robin(addresses: Vec<Public>) -> Self {
+        Payouts {
+            addresses,
+            cumulative_weights: None,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick recipients at random in proportion to their weight, every weight has to be positive
+    pub fn weighted(entries: Vec<(Public, u64)>) -> Result<Self, anyhow::Error> {
+        let mut total = 0u64;
+        let mut cumulative_weights = Vec::with_capacity(entries.len());
+        for (address, weight) in &entries {
+            if *weight == 0 {
+                return Err(anyhow!(
+                    "Payout weight for {} must be positive",
+                    address.dump_base36()
+                ));
+            }
+            total = total
+                .checked_add(*weight)
+                .ok_or_else(|| anyhow!("Payout weights add up to more than {}", u64::MAX))?;
+            cumulative_weights.push(total);
+        }
+        Ok(Payouts {
+            addresses: entries.into_iter().map(|(address, _)| address).collect(),
+            cumulative_weights: Some(cumulative_weights),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn addresses(&self) -> &[Public] {
+        &self.addresses
+    }
+
+    /// Recipient of the next template
+    pub fn next(&self) -> Public {
+        self.next_with(&mut rand::rng())
+    }
+
+    /// `next` with weighted picks rolled on `rng`
+    fn next_with(&self, rng: &mut impl Rng) -> Public {
+        let index = match &self.cumulative_weights {
+            Some(cumulative_weights) => pick_weighted(
+                cumulative_weights,
+                rng.random_range(0..*cumulative_weights.last().unwrap()),
+            ),
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.addresses.len(),
+        };
+        self.addresses[index]
+    }
+}
+
+/// Index of the entry whose weight range `roll` (in `0..total`) falls in, the first entry covers `0..cumulative_weights[0]`
+fn pick_weighted(cumulative_weights: &[u64], roll: u64) -> usize {
+    cumulative_weights.partition_point(|&cumulative| cumulative <= roll)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn address(byte: u8) -> Public {
+        Public::new_from_buf(&[byte; 32])
+    }
+
+    #[test]
+    fn zero_weight_is_rejected() {
+        assert!(Payouts::weighted(vec![(address(1), 70), (address(2), 0)]).is_err());
+    }
+
+    #[test]
+    fn overflowing_weights_are_rejected() {
+        assert!(Payouts::weighted(vec![(address(1), u64::MAX), (address(2), 1)]).is_err());
+        assert!(Payouts::weighted(vec![(address(1), u64::MAX - 1), (address(2), 1)]).is_ok());
+    }
+
+    #[test]
+    fn rolls_fall_in_their_entrys_range() {
+        let cumulative_weights = [70, 100];
+        assert_eq!(pick_weighted(&cumulative_weights, 0), 0);
+        assert_eq!(pick_weighted(&cumulative_weights, 69), 0);
+        assert_eq!(pick_weighted(&cumulative_weights, 70), 1);
+        assert_eq!(pick_weighted(&cumulative_weights, 99), 1);
+    }
+
+    #[test]
+    fn seeded_picks_split_by_weight() {
+        let payouts = Payouts::weighted(vec![(address(1), 70), (address(2), 30)]).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let draws = 10_000;
+        let first = (0..draws)
+            .filter(|_| payouts.next_with(&mut rng) == address(1))
+            .count();
+        // The seed makes the count repeatable, 2 points either way is over four standard deviations of a 70/30 split
+        assert!((6_800..=7_200).contains(&first), "{first} of {draws}");
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::anyhow;
+use tracing::warn;
+
+/// A file holding the miner's PID, removed again when dropped so every clean exit cleans it up
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the PID to `path`, failing if it already names a running process
+    /// A PID left behind by a process that is gone is replaced, the file is only ever created anew so two miners starting at once can't both take it
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let mut replaced_stale = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    file.write_all(format!("{}\n", process::id()).as_bytes())
+                        .map_err(|e| {
+                            anyhow!("Could not write --pidfile {}: {}", path.display(), e)
+                        })?;
+                    return Ok(PidFile {
+                        path: path.to_path_buf(),
+                    });
+                }
+                // Whoever recreated it after the stale one was removed is starting right now
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && replaced_stale => {
+                    return Err(anyhow!(
+                        "--pidfile {} was created by another process while replacing it, is another miner starting?",
+                        path.display()
+                    ));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let contents = fs::read_to_string(path).unwrap_or_default();
+                    match contents.trim().parse::<u32>() {
+                        Ok(pid) if is_running(pid) => {
+                            return Err(anyhow!(
+                                "--pidfile {} belongs to running process {pid}, is another miner running?",
+                                path.display()
+                            ));
+                        }
+                        _ => warn!(
+                            "Replacing stale --pidfile {} (\"{}\")",
+                            path.display(),
+                            contents.trim()
+                        ),
+                    }
+                    match fs::remove_file(path) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => {
+                            return Err(anyhow!(
+                                "Could not remove stale --pidfile {}: {}",
+                                path.display(),
+                                e
+                            ));
+                        }
+                        _ => replaced_stale = true,
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow!(
+                        "Could not write --pidfile {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Whether a process with this PID exists, signal 0 only checks that it could be signalled
+/// Permission to signal it being denied still means it is running, under another user
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // 0 and anything past pid_t would name a process group or nothing at all
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid == 0 {
+        return false;
+    }
+    // SAFETY: signal 0 sends nothing, kill only looks the process up
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to look the process up, a PID left in the file is always taken to be running
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove --pidfile {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("snap-coin-miner-{}-{name}.pid", process::id()))
+    }
+
+    #[test]
+    fn running_process_keeps_its_pidfile() {
+        let path = path("running");
+        let pidfile = PidFile::create(&path).unwrap();
+        assert!(PidFile::create(&path).is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn exited_process_leaves_a_stale_pidfile() {
+        let path = path("stale");
+        let mut exited = process::Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        fs::write(&path, format!("{}\n", exited.id())).unwrap();
+        let _pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+    }
+}
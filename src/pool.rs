@@ -0,0 +1,215 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use num_bigint::BigUint;
+use serde_json::{Value, json};
+use snap_coin::core::block::Block;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::{Mutex, broadcast},
+    time::{sleep, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    MAX_RECONNECT_DELAY,
+    lock::lock,
+    metrics::Metrics,
+    node::NodeAddress,
+    style,
+    work::{self, Submitted, WorkSource},
+};
+
+/// Connection to a mining pool speaking a Stratum-like JSON-RPC over TCP, one JSON object per line
+/// The miner sends `mining.subscribe [worker]` on connect and `mining.submit [worker, block]` for every share,
+/// the pool sends `mining.notify [block]` with a new template and `mining.set_difficulty [target]` with the 32 byte share target
+pub struct PoolClient {
+    address: NodeAddress,
+    /// `[node] connect_timeout_secs`, the same limit node connections have
+    connect_timeout: Duration,
+    worker: String,
+    /// Replaced by `run` on every reconnect, `None` while disconnected
+    writer: Mutex<Option<OwnedWriteHalf>>,
+    next_id: AtomicU64,
+    /// Ids of submissions the pool has not answered yet
+    pending_submits: std::sync::Mutex<HashSet<u64>>,
+    /// Latest template from `mining.notify`
+    job: std::sync::Mutex<Option<Block>>,
+    /// Set by `mining.set_difficulty`, shared with the mining threads
+    share_target: Arc<ArcSwap<BigUint>>,
+}
+
+impl PoolClient {
+    pub fn new(
+        address: NodeAddress,
+        connect_timeout: Duration,
+        worker: String,
+        share_target: Arc<ArcSwap<BigUint>>,
+    ) -> Self {
+        PoolClient {
+            address,
+            connect_timeout,
+            worker,
+            writer: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            pending_submits: std::sync::Mutex::new(HashSet::new()),
+            job: std::sync::Mutex::new(None),
+            share_target,
+        }
+    }
+
+    async fn send(&self, id: u64, method: &str, params: Value) -> Result<(), anyhow::Error> {
+        let mut line = json!({ "id": id, "method": method, "params": params }).to_string();
+        line.push('\n');
+
+        let mut writer = self.writer.lock().await;
+        let writer = writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not connected to pool {}", self.address))?;
+        writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Connect and subscribe, returning the lines the pool sends back
+    async fn connect(&self) -> Result<Lines<BufReader<OwnedReadHalf>>, anyhow::Error> {
+        let stream = timeout(self.connect_timeout, self.address.connect_tcp())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "connecting timed out after {}s",
+                    self.connect_timeout.as_secs()
+                )
+            })??;
+        let (reader, writer) = stream.into_split();
+        *self.writer.lock().await = Some(writer);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send(id, "mining.subscribe", json!([self.worker]))
+            .await?;
+        Ok(BufReader::new(reader).lines())
+    }
+
+    /// Forward pool jobs and share targets to the mining threads until the task is dropped
+    /// Reconnects with exponential backoff (1s, 2s, 4s... capped at 30s) whenever the connection drops
+    pub async fn run(
+        &self,
+        job_tx: broadcast::Sender<Block>,
+        epoch: Arc<AtomicU64>,
+        metrics: Arc<Metrics>,
+    ) {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match self.connect().await {
+                Ok(mut lines) => {
+                    info!("Connected to pool {}", self.address);
+                    delay = Duration::from_secs(1);
+                    loop {
+                        match lines.next_line().await {
+                            Ok(Some(line)) => {
+                                if let Err(e) = self.handle(&line, &job_tx, &epoch, &metrics).await
+                                {
+                                    warn!("Invalid pool message {line}: {e}");
+                                }
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Error: {e}");
+                                break;
+                            }
+                        }
+                    }
+                    *self.writer.lock().await = None;
+                    // Shares in flight are lost with the connection
+                    lock(&self.pending_submits).clear();
+                    warn!("Lost connection to pool {}, reconnecting", self.address);
+                }
+                Err(e) => warn!("Failed to connect to pool {}: {e}", self.address),
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    async fn handle(
+        &self,
+        line: &str,
+        job_tx: &broadcast::Sender<Block>,
+        epoch: &AtomicU64,
+        metrics: &Metrics,
+    ) -> Result<(), anyhow::Error> {
+        let message: Value = serde_json::from_str(line)?;
+        match message["method"].as_str() {
+            Some("mining.notify") => {
+                let block: Block = serde_json::from_value(message["params"][0].clone())?;
+                *lock(&self.job) = Some(block);
+                work::refresh(self, job_tx, epoch, metrics).await?;
+            }
+            Some("mining.set_difficulty") => {
+                let target: [u8; 32] = serde_json::from_value(message["params"][0].clone())?;
+                let target = BigUint::from_bytes_be(&target);
+                metrics.set_difficulty(&target);
+                self.share_target.store(Arc::new(target));
+            }
+            Some(method) => warn!("Ignoring unknown pool method {method}"),
+            // A response, only the ones to submissions are worth reporting
+            None => {
+                let is_submit = message["id"]
+                    .as_u64()
+                    .is_some_and(|id| lock(&self.pending_submits).remove(&id));
+                match (&message["error"], is_submit) {
+                    (Value::Null, true) => {
+                        metrics.shares_accepted.fetch_add(1, Ordering::Relaxed);
+                        info!("{}", style::green("Share accepted"));
+                    }
+                    (Value::Null, false) => {}
+                    (error, true) => {
+                        metrics.submit_failures.fetch_add(1, Ordering::Relaxed);
+                        warn!("{}", style::red(format!("Share rejected: {error}")));
+                    }
+                    (error, false) => warn!("Pool error: {error}"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WorkSource for PoolClient {
+    async fn current_work(&self) -> Result<Option<Block>, anyhow::Error> {
+        lock(&self.job)
+            .clone()
+            .map(Some)
+            .ok_or_else(|| anyhow!("No job from pool {} yet", self.address))
+    }
+
+    fn difficulty(&self) -> BigUint {
+        (**self.share_target.load()).clone()
+    }
+
+    /// Pool templates carry no height
+    fn height(&self) -> Option<usize> {
+        None
+    }
+
+    /// Send a share, the pool's verdict is logged by `run` when it arrives
+    async fn submit(&self, block: &Block) -> Result<Submitted, anyhow::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Registered up front, the answer can arrive before send returns
+        lock(&self.pending_submits).insert(id);
+        let sent = self
+            .send(id, "mining.submit", json!([self.worker, block]))
+            .await;
+        if sent.is_err() {
+            lock(&self.pending_submits).remove(&id);
+        }
+        sent.map(|_| Submitted::Share)
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use arc_swap::ArcSwap;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::Notify,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    footprint,
+    lock::lock,
+    miner::MiningThreads,
+    node::NodeAddress,
+    node_addresses, parse_nodes, resolve_thread_count,
+    settings::{self, MinerConfig},
+};
+
+/// Keys a reload applies to the running miner, every other change needs a restart
+const LIVE_KEYS: [&str; 3] = ["threads.count", "node.address", "node.addresses"];
+
+/// Everything a SIGHUP reload can change, and the config it was last loaded from
+pub struct Reload {
+    pub config_path: String,
+    pub loaded: MinerConfig,
+    pub thread_count_override: Option<i32>,
+    /// --force, resizing past the installed memory is refused without it
+    pub force: bool,
+    /// Argon2 memory cost of the running threads, in KiB
+    pub memory_cost: u32,
+    pub node_override: Option<NodeAddress>,
+    /// The node list is unused while pool mining
+    pub pool_mining: bool,
+    pub mining: Arc<Mutex<MiningThreads>>,
+    pub nodes: Arc<ArcSwap<Vec<NodeAddress>>>,
+    pub reconnect: Arc<Notify>,
+    pub refresh_request: Arc<Notify>,
+    /// Has the stats task start its peak, min and median over
+    pub stats_reset: Arc<AtomicBool>,
+}
+
+impl Reload {
+    /// Re-read the config on every SIGHUP until the task is dropped, a config that fails to load leaves the miner as it is
+    pub async fn run(mut self) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Could not listen for SIGHUP, config reloads are disabled: {e}");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            info!("Reloading {}", self.config_path);
+            self.stats_reset.store(true, Ordering::Relaxed);
+            match settings::load(&self.config_path) {
+                Ok(config) => {
+                    if let Err(e) = self.apply(&config) {
+                        error!("Failed to apply reloaded config: {e}");
+                    }
+                    // Even a partly applied config is the one later reloads compare against
+                    self.loaded = config;
+                }
+                Err(e) => error!("Failed to reload {}: {e}", self.config_path),
+            }
+        }
+    }
+
+    fn apply(&self, config: &MinerConfig) -> Result<(), anyhow::Error> {
+        let changed = settings::changed_keys(&self.loaded, config);
+        if changed.is_empty() {
+            info!("Config unchanged");
+        }
+        for key in changed
+            .iter()
+            .filter(|key| !LIVE_KEYS.contains(&key.as_str()))
+        {
+            warn!("{key} changed, restart the miner to apply it");
+        }
+
+        if changed.iter().any(|key| key == "threads.count") {
+            match (self.thread_count_override, config.threads.count) {
+                (Some(_), _) => warn!("threads.count changed, but --threads takes precedence"),
+                (None, None) => warn!("threads.count was removed, keeping the running threads"),
+                (None, Some(thread_count)) => {
+                    let thread_count = resolve_thread_count(thread_count)?;
+                    footprint::check(thread_count, self.memory_cost, self.force)?;
+                    let mut mining = lock(&self.mining);
+                    if thread_count != mining.thread_count() {
+                        info!(
+                            "Mining on {thread_count} threads, was {}",
+                            mining.thread_count()
+                        );
+                        mining.resize(thread_count);
+                        // The new threads are waiting for a template
+                        self.refresh_request.notify_one();
+                    }
+                }
+            }
+        }
+
+        if changed
+            .iter()
+            .any(|key| key == "node.address" || key == "node.addresses")
+        {
+            if self.pool_mining {
+                warn!("The node list changed, but is unused while pool mining");
+            } else {
+                let addresses = node_addresses(self.node_override.as_ref(), &config.node);
+                let nodes = parse_nodes(&addresses)?;
+                if nodes.is_empty() {
+                    warn!("The reloaded config has no nodes, keeping the current ones");
+                } else {
+                    info!("Switching to nodes {}", addresses.join(", "));
+                    self.nodes.store(Arc::new(nodes));
+                    self.reconnect.notify_one();
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use std::{env, path::PathBuf};
+
+use config::{Config, ConfigError, File, FileFormat, Map, Source, Value, ValueKind};
+use serde::{Deserialize, Serialize};
+use snap_coin::{
+    core::block::MAX_TRANSACTIONS_PER_BLOCK, crypto::ARGON2_CONFIG, economics::TARGET_TIME,
+};
+
+/// The whole `miner.toml`, every key besides `miner.public` (or `[[miner.payouts]]`) and `threads.count` has a default
+/// Unknown keys are ignored, so configs written for older or newer versions still load
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MinerConfig {
+    #[serde(default)]
+    pub node: NodeConfig,
+    #[serde(default)]
+    pub miner: MinerSection,
+    #[serde(default)]
+    pub threads: ThreadsConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub argon2: Argon2Config,
+    #[serde(default)]
+    pub alert: AlertConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    pub address: Option<String>,
+    /// Fallbacks, tried in order after `address`
+    pub addresses: Vec<String>,
+    pub refresh_interval_secs: u64,
+    pub submit_retries: u32,
+    /// Submit to every node in `address` and `addresses` at once rather than only the active one
+    pub broadcast_submit: bool,
+    /// Least time between two block submissions, 0 for none
+    pub min_submit_interval_ms: u64,
+    /// Pause mining while the node has no peers
+    pub require_synced: bool,
+    /// Pause mining while the node has fewer peers, 0 leaves it to `require_synced`
+    pub min_peers: usize,
+    pub target_block_time_secs: u64,
+    pub connect_timeout_secs: u64,
+    /// Applies to every request but the long lived event stream
+    pub request_timeout_secs: u64,
+    /// Requests slower than this are warned about, 0 for never
+    pub slow_request_ms: u64,
+    /// Failed refreshes and submissions in a row that pause mining, 0 for never
+    pub breaker_failures: u32,
+    pub tls: bool,
+    /// PEM CA certificates trusted instead of the bundled roots when `tls` is set
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            address: None,
+            addresses: vec![],
+            refresh_interval_secs: 3,
+            submit_retries: 3,
+            broadcast_submit: false,
+            min_submit_interval_ms: 0,
+            require_synced: true,
+            min_peers: 0,
+            target_block_time_secs: TARGET_TIME,
+            connect_timeout_secs: 10,
+            request_timeout_secs: 10,
+            slow_request_ms: 1000,
+            breaker_failures: 5,
+            tls: false,
+            ca_cert: None,
+        }
+    }
+}
+
+/// `miner.public` as either one address or a list of them
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(
+    untagged,
+    expecting = "expected a wallet address or a list of wallet addresses"
+)]
+pub enum PublicField {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PayoutEntry {
+    pub address: String,
+    pub weight: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MinerSection {
+    pub public: Option<PublicField>,
+    /// Takes precedence over `public`
+    pub payouts: Option<Vec<PayoutEntry>>,
+    pub batch_size: u64,
+    pub max_cpu_percent: u32,
+    pub pin_threads: bool,
+    pub refresh_timestamp: bool,
+    pub show_balance: bool,
+    pub balance_interval_secs: u64,
+    pub block_log_dir: Option<PathBuf>,
+    /// Mempool transactions a template takes at most, the reward transaction comes on top
+    pub max_txs_per_block: usize,
+    /// Never fetch the mempool, only the reward transaction goes into templates
+    pub empty_blocks: bool,
+    /// "base36" or "hex"
+    pub hash_format: String,
+}
+
+impl Default for MinerSection {
+    fn default() -> Self {
+        MinerSection {
+            public: None,
+            payouts: None,
+            batch_size: 20,
+            max_cpu_percent: 100,
+            pin_threads: false,
+            refresh_timestamp: true,
+            show_balance: false,
+            balance_interval_secs: 60,
+            block_log_dir: None,
+            max_txs_per_block: MAX_TRANSACTIONS_PER_BLOCK - 1,
+            empty_blocks: false,
+            hash_format: "base36".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct ThreadsConfig {
+    /// Required unless `--threads` is given, `-1` for every available core
+    pub count: Option<i32>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    pub interval_secs: u64,
+    pub format: String,
+    pub ema_alpha: f64,
+    pub csv_path: Option<PathBuf>,
+    /// Seconds after startup whose hashes stay out of the statistics
+    pub warmup_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            interval_secs: 3,
+            format: "human".to_string(),
+            ema_alpha: 0.3,
+            csv_path: None,
+            warmup_secs: 5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct PoolConfig {
+    /// Replaces solo mining against the node when set
+    pub url: Option<String>,
+    /// Defaults to the (first) payout address
+    pub worker: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct MetricsConfig {
+    pub listen: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct NotifyConfig {
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// In H/s, compared against the EMA hash rate
+    pub min_hashrate: Option<f64>,
+    /// Consecutive stats intervals the hash rate has to stay below `min_hashrate` before the alert is raised
+    pub min_hashrate_intervals: u32,
+    /// Solo mining only, seconds without an accepted block before warning
+    pub no_block_secs: Option<u64>,
+    /// Also POST alerts to `notify.webhook_url`
+    pub webhook: bool,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            min_hashrate: None,
+            min_hashrate_intervals: 5,
+            no_block_secs: None,
+            webhook: false,
+        }
+    }
+}
+
+/// How the mining threads hold their Argon2 memory, and overrides of the network's Argon2 parameters, only useful against a node patched to match
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    /// Allocate each thread's Argon2 memory once and reuse it for every hash
+    pub preallocate: bool,
+    /// With `preallocate`, ask Linux for transparent huge pages to back it
+    pub huge_pages: bool,
+    /// In KiB
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    /// Only 32 is accepted, hashes are fixed size
+    pub output_length: usize,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Argon2Config {
+            preallocate: true,
+            huge_pages: false,
+            memory_cost: ARGON2_CONFIG.memory_cost,
+            time_cost: ARGON2_CONFIG.time_cost,
+            parallelism: ARGON2_CONFIG.parallelism,
+            output_length: ARGON2_CONFIG.output_length.unwrap_or(32),
+        }
+    }
+}
+
+/// Read the toml file at `path`, with environment overrides on top
+pub fn load(path: &str) -> Result<MinerConfig, ConfigError> {
+    Config::builder()
+        .add_source(File::new(path, FileFormat::Toml))
+        .add_source(EnvOverrides)
+        .build()?
+        .try_deserialize()
+}
+
+/// Dotted keys whose value differs between two configs, lists count as a single key
+pub fn changed_keys(old: &MinerConfig, new: &MinerConfig) -> Vec<String> {
+    fn diff(
+        prefix: &str,
+        old: &serde_json::Value,
+        new: &serde_json::Value,
+        keys: &mut Vec<String>,
+    ) {
+        match (old, new) {
+            (serde_json::Value::Object(old), serde_json::Value::Object(new)) => {
+                for (key, old_value) in old {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{prefix}.{key}")
+                    };
+                    diff(&path, old_value, &new[key], keys);
+                }
+            }
+            (old, new) if old != new => keys.push(prefix.to_string()),
+            _ => {}
+        }
+    }
+
+    let mut keys = vec![];
+    // Both sides serialize every field, so walking the old one's keys covers the new one's too
+    if let (Ok(old), Ok(new)) = (serde_json::to_value(old), serde_json::to_value(new)) {
+        diff("", &old, &new, &mut keys);
+    }
+    keys
+}
+
+/// Prefix of the environment variables that override config keys
+const ENV_PREFIX: &str = "SNAP_MINER_";
+
+/// Keys whose environment value is a comma separated list, `miner.public` only when it holds a comma
+const LIST_KEYS: [&str; 2] = ["node.addresses", "miner.public"];
+
+/// Config source reading `SNAP_MINER_<SECTION>_<KEY>` environment variables as `<section>.<key>`, eg. `SNAP_MINER_NODE_ADDRESS`
+/// Sections are single words, so everything after the first underscore is the key (`SNAP_MINER_NODE_REFRESH_INTERVAL_SECS`)
+#[derive(Clone, Debug)]
+pub struct EnvOverrides;
+
+impl Source for EnvOverrides {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let origin = "the environment".to_string();
+        let mut overrides = Map::new();
+        // Other programs' variables aren't read at all, they may hold anything
+        for (name, value) in env::vars_os() {
+            if !name.to_string_lossy().starts_with(ENV_PREFIX) {
+                continue;
+            }
+            let name = name.into_string().map_err(|name| {
+                ConfigError::Message(format!(
+                    "Environment variable {} is not valid UTF-8",
+                    name.to_string_lossy()
+                ))
+            })?;
+            let value = value.into_string().map_err(|_| {
+                ConfigError::Message(format!("Environment variable {name} is not valid UTF-8"))
+            })?;
+            let Some((section, key)) = name
+                .strip_prefix(ENV_PREFIX)
+                .and_then(|name| name.split_once('_'))
+            else {
+                continue;
+            };
+            let key = format!("{}.{}", section.to_lowercase(), key.to_lowercase());
+            let kind = if LIST_KEYS.contains(&key.as_str())
+                && (key != "miner.public" || value.contains(','))
+            {
+                ValueKind::Array(
+                    value
+                        .split(',')
+                        .map(|item| Value::new(Some(&origin), item.trim()))
+                        .collect(),
+                )
+            } else {
+                ValueKind::String(value)
+            };
+            overrides.insert(key, Value::new(Some(&origin), kind));
+        }
+        Ok(overrides)
+    }
+}
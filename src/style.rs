@@ -0,0 +1,78 @@
+use std::{
+    env,
+    fmt::Display,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::anyhow;
+
+/// Set once at startup from `--color`, every helper below leaves text untouched while it is off
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When log output is colored
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// Only on a terminal, and never with `NO_COLOR` set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(choice: &str) -> Result<Self, anyhow::Error> {
+        match choice {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow!(
+                "Invalid --color \"{}\", expected \"auto\", \"always\" or \"never\"",
+                choice
+            )),
+        }
+    }
+}
+
+/// Decide whether to color and remember it for the helpers, the result also goes to the log formatter
+pub fn init(choice: ColorChoice) -> bool {
+    let enabled = match choice {
+        ColorChoice::Auto => {
+            // Logs go to stdout, see https://no-color.org for NO_COLOR
+            io::stdout().is_terminal()
+                && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        }
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+    enabled
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Escapes within `text` itself are shown literally, the log formatter stops sanitizing them while colored
+fn paint(code: &str, text: impl Display) -> String {
+    if enabled() {
+        let text = text.to_string().replace('\x1b', "\\x1b");
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Accepted blocks and shares
+pub fn green(text: impl Display) -> String {
+    paint("32", text)
+}
+
+/// Failed or rejected submissions
+pub fn red(text: impl Display) -> String {
+    paint("31", text)
+}
+
+/// The periodic stats lines
+pub fn dim(text: impl Display) -> String {
+    paint("2", text)
+}
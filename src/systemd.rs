@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// Every notification is dropped unless systemd set `NOTIFY_SOCKET`, and off unix
+#[cfg(unix)]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(&[state]) {
+        tracing::warn!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Mining has started on the first block template
+pub fn ready() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Ready);
+}
+
+/// Proof of life for `WatchdogSec`, sent every stats interval
+pub fn watchdog() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Watchdog);
+}
+
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Stopping);
+}
+
+/// The `WatchdogSec` systemd expects a ping within, if the watchdog is on for this process
+pub fn watchdog_timeout() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        sd_notify::watchdog_enabled()
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+};
+use tracing::{Instrument, warn};
+
+use crate::node::NodeAddress;
+
+/// How long a tunnel waits for the client it was opened for
+const TUNNEL_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the connection from `peer` to the tunnel listening on `tunnel` was opened by this process
+/// Looks for the connecting socket among this process's file descriptors, so another local user can't take over the node connection
+#[cfg(target_os = "linux")]
+fn opened_here(peer: SocketAddr, tunnel: SocketAddr) -> bool {
+    let Ok(fds) = std::fs::read_dir("/proc/self/fd") else {
+        return false;
+    };
+    fds.flatten()
+        .filter_map(|fd| fd.file_name().to_str()?.parse::<libc::c_int>().ok())
+        .any(|fd| {
+            socket_address(fd, libc::getsockname) == Some(peer)
+                && socket_address(fd, libc::getpeername) == Some(tunnel)
+        })
+}
+
+/// Only Linux lists a process's sockets, elsewhere the first local connection is taken
+#[cfg(not(target_os = "linux"))]
+fn opened_here(_peer: SocketAddr, _tunnel: SocketAddr) -> bool {
+    true
+}
+
+/// The IPv4 address `query` (`getsockname` or `getpeername`) reports for `fd`, `None` for anything but an IPv4 socket
+#[cfg(target_os = "linux")]
+fn socket_address(
+    fd: libc::c_int,
+    query: unsafe extern "C" fn(
+        libc::c_int,
+        *mut libc::sockaddr,
+        *mut libc::socklen_t,
+    ) -> libc::c_int,
+) -> Option<SocketAddr> {
+    // SAFETY: sockaddr_in is plain data, and query writes at most len bytes into it, failing on a descriptor that isn't a socket
+    let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let result = unsafe {
+        query(
+            fd,
+            (&mut address as *mut libc::sockaddr_in).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 || address.sin_family != libc::AF_INET as libc::sa_family_t {
+        return None;
+    }
+    Some(SocketAddr::from((
+        Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+        u16::from_be(address.sin_port),
+    )))
+}
+
+/// TLS to the node, the snap-coin `Client` only speaks plain TCP so it connects through a local tunnel per connection
+#[derive(Clone)]
+pub struct NodeTls {
+    connector: TlsConnector,
+}
+
+impl NodeTls {
+    /// Trust the bundled web PKI roots, or only the CA certificates in the PEM file at `ca_cert`
+    pub fn new(ca_cert: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let mut roots = RootCertStore::empty();
+        match ca_cert {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        anyhow!("Could not read node.ca_cert {}: {}", path.display(), e)
+                    })?;
+                if certs.is_empty() {
+                    return Err(anyhow!(
+                        "node.ca_cert {} holds no PEM certificates",
+                        path.display()
+                    ));
+                }
+                for cert in certs {
+                    roots.add(cert).map_err(|e| {
+                        anyhow!(
+                            "Invalid certificate in node.ca_cert {}: {}",
+                            path.display(),
+                            e
+                        )
+                    })?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(NodeTls {
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Complete a TLS handshake with `node` at `address` and return a loopback address forwarding to it
+    /// The tunnel serves a single connection from this process and closes with it, on Linux it turns away connections from other processes
+    pub async fn tunnel(
+        &self,
+        node: &NodeAddress,
+        address: SocketAddr,
+    ) -> Result<SocketAddr, io::Error> {
+        let server_name = ServerName::try_from(node.host().to_string()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} can't be checked against a TLS certificate: {e}",
+                    node.host()
+                ),
+            )
+        })?;
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        let mut tls = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("TLS handshake failed: {e}")))?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let local = listener.local_addr()?;
+        tokio::spawn(
+            async move {
+                let accepted = timeout(TUNNEL_ACCEPT_TIMEOUT, async {
+                    loop {
+                        let (plain, peer) = listener.accept().await?;
+                        if opened_here(peer, local) {
+                            return Ok::<_, io::Error>(plain);
+                        }
+                        warn!("TLS tunnel turned away a connection from {peer}, another local process opened it");
+                    }
+                });
+                let mut plain = match accepted.await {
+                    Ok(Ok(plain)) => plain,
+                    Ok(Err(e)) => {
+                        warn!("TLS tunnel failed to accept: {e}");
+                        return;
+                    }
+                    Err(_) => return,
+                };
+                // Ends once either side closes, which also drops the node connection
+                let _ = plain.set_nodelay(true);
+                let _ = copy_bidirectional(&mut plain, &mut tls).await;
+            }
+            .in_current_span(),
+        );
+        Ok(local)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_this_process_opens_the_tunnel() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let tunnel = listener.local_addr().unwrap();
+
+        let own = TcpStream::connect(tunnel).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, own.local_addr().unwrap());
+        assert!(opened_here(peer, tunnel));
+
+        // Holds its connection open until the check is done
+        let mut other = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "exec 3<>/dev/tcp/{}/{}; sleep 5",
+                tunnel.ip(),
+                tunnel.port()
+            ))
+            .spawn()
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert!(!opened_here(peer, tunnel));
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Direction, Layout},
+    text::Line,
+    widgets::{Bar, BarChart, Block, Paragraph, Sparkline},
+};
+use tokio::sync::Notify;
+use tracing::{info_span, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+use crate::{format_hash_rate, format_uptime, lock::lock, metrics::Metrics};
+
+/// How often the dashboard is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Log lines kept for the dashboard's log pane
+const LOG_LINES: usize = 200;
+
+/// Redraws of total hash rate history shown, two minutes at the redraw interval
+const RATE_SAMPLES: usize = 240;
+
+/// Where log lines go, the dashboard's log pane while it is up and stdout before and after
+pub struct TuiLog {
+    lines: Mutex<VecDeque<String>>,
+    active: AtomicBool,
+}
+
+impl TuiLog {
+    pub fn new() -> Arc<Self> {
+        Arc::new(TuiLog {
+            lines: Mutex::new(VecDeque::with_capacity(LOG_LINES)),
+            active: AtomicBool::new(false),
+        })
+    }
+
+    /// The writer for the log formatter
+    pub fn make_writer(self: &Arc<Self>) -> BoxMakeWriter {
+        let log = self.clone();
+        BoxMakeWriter::new(move || TuiLogWriter(log.clone()))
+    }
+}
+
+struct TuiLogWriter(Arc<TuiLog>);
+
+impl Write for TuiLogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.0.active.load(Ordering::Relaxed) {
+            return io::stdout().write(buf);
+        }
+        let mut lines = lock(&self.0.lines);
+        // The formatter writes one whole event per writer
+        for line in String::from_utf8_lossy(buf).lines() {
+            if lines.len() == LOG_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.0.active.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            io::stdout().flush()
+        }
+    }
+}
+
+/// `--tui`, a live view of the stats task's numbers in place of the scrolling log
+pub struct Dashboard {
+    pub metrics: Arc<Metrics>,
+    pub log: Arc<TuiLog>,
+    /// Notified on q or Ctrl-C, which raw mode keeps from raising SIGINT
+    pub quit: Arc<Notify>,
+    /// Set by main to close the dashboard and hand the terminal back
+    pub stop: Arc<AtomicBool>,
+}
+
+impl Dashboard {
+    /// Take over the terminal on a thread of its own until `stop` is set, logging plainly instead if that fails
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let _span = info_span!("tui").entered();
+            let mut terminal = match ratatui::try_init() {
+                Ok(terminal) => terminal,
+                Err(e) => {
+                    warn!("Could not start the dashboard, logging plainly: {e}");
+                    return;
+                }
+            };
+            self.log.active.store(true, Ordering::Relaxed);
+            if let Err(e) = self.run(&mut terminal) {
+                warn!("The dashboard failed: {e}");
+            }
+            ratatui::restore();
+            // Whatever was logged while the dashboard was up, so the last lines survive on the terminal
+            self.log.active.store(false, Ordering::Relaxed);
+            let mut stdout = io::stdout();
+            for line in lock(&self.log.lines).iter().rev().take(20).rev() {
+                let _ = writeln!(stdout, "{line}");
+            }
+        })
+    }
+
+    fn run(&self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        let start = Instant::now();
+        let mut rates = VecDeque::with_capacity(RATE_SAMPLES);
+        while !self.stop.load(Ordering::Relaxed) {
+            if rates.len() == RATE_SAMPLES {
+                rates.pop_front();
+            }
+            rates.push_back(self.metrics.hash_rate());
+            terminal.draw(|frame| self.draw(frame, start, &rates))?;
+
+            if event::poll(REDRAW_INTERVAL)?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && (key.code == KeyCode::Char('q')
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)))
+            {
+                self.quit.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    fn draw(&self, frame: &mut Frame, start: Instant, rates: &VecDeque<f64>) {
+        let metrics = &self.metrics;
+        let thread_rates = lock(&metrics.thread_hash_rates).clone();
+        let [header, history, threads, logs] = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(5),
+                Constraint::Length(5),
+                Constraint::Length(thread_rates.len().max(1) as u16 + 2),
+                Constraint::Min(3),
+            ])
+            .areas(frame.area());
+
+        let (rate, units) = format_hash_rate(metrics.hash_rate());
+        let (ema, ema_units) = format_hash_rate(metrics.ema_hash_rate());
+        let last_block = match metrics.last_block_time.load(Ordering::Relaxed) {
+            0 => "none yet".to_string(),
+            time => format!(
+                "{} ago",
+                format_uptime(Duration::from_secs(
+                    (chrono::Utc::now().timestamp() as u64).saturating_sub(time)
+                ))
+            ),
+        };
+        let summary = vec![
+            Line::from(format!(
+                "Hash rate: {rate:.2} {units}   EMA: {ema:.2} {ema_units}   Uptime: {}",
+                format_uptime(start.elapsed())
+            )),
+            Line::from(format!(
+                "Height: {}   Difficulty: {:.3e}",
+                metrics
+                    .height()
+                    .map_or("unknown".to_string(), |height| height.to_string()),
+                metrics.difficulty()
+            )),
+            Line::from(format!(
+                "Blocks: {} accepted, {} rejected   Last block: {last_block}",
+                metrics.blocks_found.load(Ordering::Relaxed),
+                metrics.blocks_rejected.load(Ordering::Relaxed)
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(summary).block(Block::bordered().title(" snap-coin-miner (q to quit) ")),
+            header,
+        );
+
+        // Bars take whole numbers, hundredths of a hash keep slow Argon2 rates apart
+        let history_data: Vec<u64> = rates
+            .iter()
+            .skip(
+                rates
+                    .len()
+                    .saturating_sub(history.width.saturating_sub(2) as usize),
+            )
+            .map(|rate| (rate * 100.0) as u64)
+            .collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(" Total hash rate "))
+                .data(&history_data),
+            history,
+        );
+
+        let bars: Vec<Bar> = thread_rates
+            .iter()
+            .enumerate()
+            .map(|(i, rate)| {
+                let (display, units) = format_hash_rate(*rate);
+                Bar::default()
+                    .label(Line::from(format!("{i:>3}")))
+                    .value((rate * 100.0) as u64)
+                    .text_value(format!("{display:.2} {units}"))
+            })
+            .collect();
+        frame.render_widget(
+            BarChart::horizontal(bars)
+                .block(Block::bordered().title(" Threads "))
+                .bar_width(1)
+                .bar_gap(0),
+            threads,
+        );
+
+        let visible = logs.height.saturating_sub(2) as usize;
+        let lines = lock(&self.log.lines);
+        let log_lines: Vec<Line> = lines
+            .iter()
+            .skip(lines.len().saturating_sub(visible))
+            .map(|line| Line::from(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(log_lines).block(Block::bordered().title(" Log ")),
+            logs,
+        );
+    }
+}
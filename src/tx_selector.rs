@@ -0,0 +1,108 @@
+use snap_coin::core::transaction::Transaction;
+use tracing::info;
+
+/// Picks and orders the mempool transactions a node template takes
+/// The mempool handed over is already free of transactions about to expire
+pub trait TxSelector: Send + Sync {
+    fn select(&self, mempool: Vec<Transaction>) -> Vec<Transaction>;
+
+    /// Whether `select` takes anything from the mempool, templates for a selector that doesn't are built without fetching it
+    fn wants_mempool(&self) -> bool {
+        true
+    }
+}
+
+/// Every transaction, in the order the node lists them
+pub struct AllTxs;
+
+impl TxSelector for AllTxs {
+    fn select(&self, mempool: Vec<Transaction>) -> Vec<Transaction> {
+        mempool
+    }
+}
+
+/// `[miner] empty_blocks`, only the reward transaction goes into templates
+pub struct NoTxs;
+
+impl TxSelector for NoTxs {
+    fn select(&self, _mempool: Vec<Transaction>) -> Vec<Transaction> {
+        vec![]
+    }
+
+    fn wants_mempool(&self) -> bool {
+        false
+    }
+}
+
+/// `[miner] max_txs_per_block`, the first `max` of the transactions `inner` selects
+pub struct AtMost {
+    pub max: usize,
+    pub inner: Box<dyn TxSelector>,
+}
+
+impl TxSelector for AtMost {
+    fn select(&self, mempool: Vec<Transaction>) -> Vec<Transaction> {
+        let mut selected = self.inner.select(mempool);
+        if selected.len() > self.max {
+            info!(
+                "Template includes {} of {} mempool transactions",
+                self.max,
+                selected.len()
+            );
+            selected.truncate(self.max);
+        }
+        selected
+    }
+
+    fn wants_mempool(&self) -> bool {
+        self.max > 0 && self.inner.wants_mempool()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mempool(len: u64) -> Vec<Transaction> {
+        (0..len)
+            .map(|nonce| Transaction {
+                inputs: vec![],
+                outputs: vec![],
+                transaction_id: None,
+                nonce,
+                timestamp: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn at_most_keeps_the_first_transactions() {
+        let selector = AtMost {
+            max: 2,
+            inner: Box::new(AllTxs),
+        };
+        let selected = selector.select(mempool(5));
+        assert_eq!(
+            selected.iter().map(|tx| tx.nonce).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert_eq!(selector.select(mempool(1)).len(), 1);
+    }
+
+    #[test]
+    fn empty_selections_skip_the_mempool() {
+        assert!(AllTxs.wants_mempool());
+        assert!(!NoTxs.wants_mempool());
+        assert!(NoTxs.select(mempool(3)).is_empty());
+        let none = AtMost {
+            max: 0,
+            inner: Box::new(AllTxs),
+        };
+        assert!(!none.wants_mempool());
+        let empty = AtMost {
+            max: 10,
+            inner: Box::new(NoTxs),
+        };
+        assert!(!empty.wants_mempool());
+    }
+}
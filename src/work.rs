@@ -0,0 +1,331 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use num_bigint::BigUint;
+use snap_coin::{
+    core::block::Block,
+    economics::{DEV_WALLET, to_snap},
+};
+use tokio::sync::{Notify, broadcast, mpsc};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    hash_format,
+    lock::lock,
+    metrics::{Metrics, biguint_to_f64},
+    miner::FoundBlock,
+    notify::BlockNotifier,
+    style,
+};
+
+/// What a successful submission means for the blocks being mined
+pub enum Submitted {
+    /// The block made it into the chain, so every template built on the old tip is stale
+    Block,
+    /// A share handed to a pool, which reports its verdict on its own and pushes new templates itself
+    Share,
+    /// Taken without a word by a node that had seen the block already, nothing new went into the chain
+    Duplicate,
+    /// Turned down with the given reason because the chain moved past the template
+    Stale(String),
+    /// Turned down with the given reason for breaking the rules, eg. a bad transaction in the template
+    Invalid(String),
+}
+
+/// Where block templates come from and where found blocks go
+pub trait WorkSource: Send + Sync {
+    /// A fresh block template to mine on, `None` while the last one is still as good as a new one
+    fn current_work(&self) -> impl Future<Output = Result<Option<Block>, anyhow::Error>> + Send;
+
+    /// Target a hash has to beat to be worth submitting, as of the latest `current_work`
+    fn difficulty(&self) -> BigUint;
+
+    /// Chain height the latest `current_work` template would be mined at, if the source knows it
+    fn height(&self) -> Option<usize>;
+
+    /// Have the next `current_work` hand out a template even if nothing changed, for threads waiting on one
+    fn forget_template(&self) {}
+
+    /// Hand a found block over
+    fn submit(
+        &self,
+        block: &Block,
+    ) -> impl Future<Output = Result<Submitted, anyhow::Error>> + Send;
+}
+
+/// Fetch the source's current work and broadcast it to the mining threads
+pub async fn refresh(
+    source: &impl WorkSource,
+    job_tx: &broadcast::Sender<Block>,
+    epoch: &AtomicU64,
+    metrics: &Metrics,
+) -> Result<(), anyhow::Error> {
+    let Some(block) = source.current_work().await? else {
+        // Swapping in the same work would only reset every thread's walk
+        debug!("Tip and mempool unchanged, keeping the current template");
+        return Ok(());
+    };
+    let difficulty = source.difficulty();
+    let height = source.height().map(|height| height as u64);
+    let previous_difficulty = metrics.difficulty();
+    let new_difficulty = biguint_to_f64(&difficulty);
+    // Zero until the first template
+    if previous_difficulty != 0.0 && previous_difficulty != new_difficulty {
+        debug!("Difficulty changed from {previous_difficulty:.3e} to {new_difficulty:.3e}");
+    }
+    debug!(
+        "Template refreshed (txs: {}, timestamp: {})",
+        block.transactions.len(),
+        block.timestamp
+    );
+    metrics.set_height(height);
+    metrics.set_difficulty(&difficulty);
+    metrics.set_block_target(&BigUint::from_bytes_be(&block.meta.block_pow_difficulty));
+    // Every template the threads get, a changed mempool or a reorg can replace it at the same height
+    if let Some(height) = height {
+        info!(
+            "Now mining height {height} (txs: {}, diff: {:.3e}, reward: {} SNAP)",
+            block.transactions.len(),
+            biguint_to_f64(&difficulty),
+            to_snap(miner_reward(&block))
+        );
+    }
+    // Bump before sending, so a thread that sees the new epoch always finds this block queued
+    epoch.fetch_add(1, Ordering::Release);
+    job_tx.send(block)?;
+    Ok(())
+}
+
+/// Send the threads a template on every `refresh_request`, even one the source would skip as unchanged
+/// The threads stopped on their old one when the request was made, `refresh` is whatever fetches and sends the next
+pub async fn answer_refresh_requests(
+    source: &impl WorkSource,
+    refresh_request: &Notify,
+    refresh: impl Fn(),
+) {
+    loop {
+        refresh_request.notified().await;
+        source.forget_template();
+        refresh();
+    }
+}
+
+/// What `block` pays its miner in nano, the reward transaction's outputs besides the dev fee
+/// snap-coin transactions carry no fees, the block reward is all a block earns
+pub fn miner_reward(block: &Block) -> u64 {
+    block
+        .transactions
+        .iter()
+        .filter(|tx| tx.inputs.is_empty())
+        .flat_map(|tx| &tx.outputs)
+        .filter(|output| output.receiver != DEV_WALLET)
+        .map(|output| output.amount)
+        .sum()
+}
+
+/// How many of the latest submitted block hashes are remembered to drop duplicates
+const RECENT_SUBMISSIONS: usize = 64;
+
+/// Submit found blocks until every mining thread has dropped its sender, so blocks found during shutdown still get submitted
+/// This is the only place blocks are submitted from, a block whose hash was recently submitted is dropped
+/// Accepted blocks are handed to `notifier`
+pub async fn submit_found(
+    source: &impl WorkSource,
+    mut submission_rx: mpsc::Receiver<FoundBlock>,
+    dry_run: bool,
+    epoch: &AtomicU64,
+    refresh_request: &Notify,
+    metrics: &Metrics,
+    notifier: &BlockNotifier,
+) {
+    let mut recent = VecDeque::with_capacity(RECENT_SUBMISSIONS);
+    while let Some(found) = submission_rx.recv().await {
+        let candidate = &found.block;
+        let hash = candidate.meta.hash.unwrap();
+        if recent.contains(&hash) {
+            warn!(
+                "Dropping duplicate block {} from thread {}",
+                hash_format::show(&hash),
+                found.thread_id
+            );
+            continue;
+        }
+        if recent.len() == RECENT_SUBMISSIONS {
+            recent.pop_front();
+        }
+        recent.push_back(hash);
+        if dry_run {
+            info!(
+                "Dry run, not submitting block {} (nonce {})",
+                hash_format::show(&hash),
+                candidate.nonce
+            );
+            continue;
+        }
+        let height = source.height();
+        let submitted = source.submit(candidate).await;
+        match &submitted {
+            Ok(_) => metrics.node_breaker.succeeded(),
+            Err(_) => metrics.node_breaker.failed(epoch),
+        }
+        match submitted {
+            Ok(Submitted::Block) => {
+                // Every thread is now mining on a stale tip, stop them and fetch the next template
+                epoch.fetch_add(1, Ordering::Release);
+                refresh_request.notify_one();
+                metrics.blocks_found.fetch_add(1, Ordering::Relaxed);
+                metrics
+                    .rewards_earned
+                    .fetch_add(miner_reward(candidate), Ordering::Relaxed);
+                metrics
+                    .last_block_time
+                    .store(chrono::Utc::now().timestamp() as u64, Ordering::Relaxed);
+                lock(&metrics.block_hashes).push(found.hashes_to_find);
+                notifier.block_accepted(&found, height);
+            }
+            Ok(Submitted::Share) => {}
+            Ok(Submitted::Duplicate) => {
+                metrics.blocks_duplicate.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Block {} was already known to the node, nothing was added to the chain",
+                    hash_format::show(&hash)
+                );
+                epoch.fetch_add(1, Ordering::Release);
+                refresh_request.notify_one();
+            }
+            Ok(Submitted::Stale(reason)) => {
+                metrics.blocks_stale.fetch_add(1, Ordering::Relaxed);
+                rejected(
+                    metrics,
+                    epoch,
+                    refresh_request,
+                    format!("Block rejected as stale: {reason}"),
+                );
+            }
+            Ok(Submitted::Invalid(reason)) => rejected(
+                metrics,
+                epoch,
+                refresh_request,
+                format!("Block rejected as invalid: {reason}"),
+            ),
+            Err(e) => {
+                metrics.submit_errors.fetch_add(1, Ordering::Relaxed);
+                metrics.submit_failures.fetch_add(1, Ordering::Relaxed);
+                error!("{}", style::red(format!("Error: {:?}", e)));
+            }
+        }
+    }
+}
+
+/// Count and log a block the node turned down
+fn rejected(metrics: &Metrics, epoch: &AtomicU64, refresh_request: &Notify, message: String) {
+    metrics.blocks_rejected.fetch_add(1, Ordering::Relaxed);
+    metrics.submit_failures.fetch_add(1, Ordering::Relaxed);
+    warn!("{}", style::red(message));
+    // Retrying the same template won't help, move to the current tip instead
+    epoch.fetch_add(1, Ordering::Release);
+    refresh_request.notify_one();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use snap_coin::crypto::Hash;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::mock::{self, MockSource};
+
+    #[tokio::test]
+    async fn accepted_block_moves_the_template_to_the_new_tip() {
+        let source = Arc::new(MockSource::new(mock::template(
+            [0xffu8; 32],
+            Hash::new_from_buf([1u8; 32]),
+        )));
+        let (job_tx, mut job_rx) = broadcast::channel(4);
+        let epoch = Arc::new(AtomicU64::new(0));
+        let metrics = Arc::new(Metrics::default());
+        let refresh_request = Arc::new(Notify::new());
+        refresh(&*source, &job_tx, &epoch, &metrics).await.unwrap();
+        let mut block = job_rx.recv().await.unwrap();
+
+        // Refreshing on its own task like the job task does
+        let answering = tokio::spawn({
+            let source = source.clone();
+            let job_tx = job_tx.clone();
+            let epoch = epoch.clone();
+            let metrics = metrics.clone();
+            let refresh_request = refresh_request.clone();
+            async move {
+                answer_refresh_requests(&*source, &refresh_request, || {
+                    let source = source.clone();
+                    let job_tx = job_tx.clone();
+                    let epoch = epoch.clone();
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        refresh(&*source, &job_tx, &epoch, &metrics).await.unwrap()
+                    });
+                })
+                .await
+            }
+        });
+        let next_template = async |job_rx: &mut broadcast::Receiver<Block>| {
+            timeout(Duration::from_secs(1), job_rx.recv())
+                .await
+                .expect("no template sent after the refresh request")
+                .unwrap()
+        };
+
+        // Every hash beats an all ones target
+        block.meta.hash = Some(
+            mock::test_pow()
+                .hash(&block.get_hashing_buf().unwrap())
+                .unwrap(),
+        );
+        let (submission_tx, submission_rx) = mpsc::channel(1);
+        submission_tx
+            .send(FoundBlock {
+                block: block.clone(),
+                thread_id: 0,
+                time_to_find: Duration::ZERO,
+                hashes_to_find: 1,
+            })
+            .await
+            .unwrap();
+        drop(submission_tx);
+        let notifier = BlockNotifier {
+            webhook: None,
+            block_log_dir: None,
+        };
+        let epoch_before = epoch.load(Ordering::Acquire);
+        submit_found(
+            &*source,
+            submission_rx,
+            false,
+            &epoch,
+            &refresh_request,
+            &metrics,
+            &notifier,
+        )
+        .await;
+        assert_eq!(metrics.blocks_found.load(Ordering::Relaxed), 1);
+        assert!(epoch.load(Ordering::Acquire) > epoch_before);
+
+        // The refresh the accepted block asked for builds on it
+        let next = next_template(&mut job_rx).await;
+        assert_ne!(next.meta.previous_block, block.meta.previous_block);
+        assert_eq!(Some(next.meta.previous_block), block.meta.hash);
+
+        // An unchanged tip alone sends nothing, a refresh request still does
+        refresh(&*source, &job_tx, &epoch, &metrics).await.unwrap();
+        assert!(job_rx.try_recv().is_err());
+        refresh_request.notify_one();
+        let again = next_template(&mut job_rx).await;
+        assert_eq!(again.meta.previous_block, next.meta.previous_block);
+        answering.abort();
+    }
+}